@@ -0,0 +1,4446 @@
+use std::{fs, collections::HashMap, fmt::Display, path::PathBuf};
+
+#[allow(non_camel_case_types)]
+type INT_TYPE = i64;
+
+#[derive(Debug, Clone)]
+pub enum Token {
+    Ident(String),
+    Int(INT_TYPE),
+    Str(String),
+    LParen,
+    RParen,
+    Bang,
+}
+
+impl Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Token::Ident(s) => write!(f, "{s}"),
+            Token::Int(i) => write!(f, "{i}"),
+            Token::Str(s) => write!(f, "{s:?}"),
+            Token::LParen => write!(f, "("),
+            Token::RParen => write!(f, ")"),
+            Token::Bang => write!(f, "!"),
+        }
+    }
+}
+
+// what the stacks hold; integers stay integers so arithmetic doesn't format and
+// parse them, but the language is loosely typed and both kinds convert freely
+#[derive(Clone)]
+enum Value {
+    Int(INT_TYPE),
+    Str(String),
+    // a value annotated by `tag`, which only shows in `debug` and traces; moving the value
+    // around keeps the tag, but anything computed from it starts out untagged
+    Tagged(Box<Value>, String),
+}
+
+impl Value {
+    fn into_string(self) -> String {
+        match self {
+            Value::Int(i) => i.to_string(),
+            Value::Str(s) => s,
+            Value::Tagged(value, _) => value.into_string(),
+        }
+    }
+
+    fn to_int(&self) -> Option<INT_TYPE> {
+        match self {
+            Value::Int(i) => Some(*i),
+            Value::Str(s) => s.parse().ok(),
+            Value::Tagged(value, _) => value.to_int(),
+        }
+    }
+
+    // of the value as text, in bytes
+    fn len(&self) -> usize {
+        match self {
+            Value::Int(i) => i.to_string().len(),
+            Value::Str(s) => s.len(),
+            Value::Tagged(value, _) => value.len(),
+        }
+    }
+
+    fn untagged(self) -> Value {
+        match self {
+            Value::Tagged(value, _) => *value,
+            value => value,
+        }
+    }
+
+    fn tag(&self) -> Option<&str> {
+        match self {
+            Value::Tagged(_, tag) => Some(tag),
+            _ => None,
+        }
+    }
+}
+
+// values are equal when their text is, so `1 "1" ==` holds; tags don't count
+impl PartialEq for Value {
+    fn eq(&self, other: &Value) -> bool {
+        match (self, other) {
+            (Value::Tagged(x, _), y) | (y, Value::Tagged(x, _)) => **x == *y,
+            (Value::Int(x), Value::Int(y)) => x == y,
+            (Value::Str(x), Value::Str(y)) => x == y,
+            (Value::Int(i), Value::Str(s)) | (Value::Str(s), Value::Int(i)) => *s == i.to_string(),
+        }
+    }
+}
+
+impl Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Value::Int(i) => write!(f, "{i}"),
+            Value::Str(s) => write!(f, "{s}"),
+            Value::Tagged(value, _) => write!(f, "{value}"),
+        }
+    }
+}
+
+// shown as text, the same as before values were typed
+impl std::fmt::Debug for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Value::Int(i) => write!(f, "\"{i}\""),
+            Value::Str(s) => write!(f, "{s:?}"),
+            Value::Tagged(value, tag) => write!(f, "{value:?} #{tag}"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum RuntimeError {
+    StackUnderflow { stack: usize, needed: usize, available: usize },
+    UnknownInstruction(String),
+    UnknownLabel(String),
+    // a value that had to be an integer but isn't one
+    InvalidInt(String),
+    InvalidChar(INT_TYPE),
+    Other(String),
+    // where in the source another error happened
+    At { file: String, line: usize, column: usize, error: Box<RuntimeError> },
+    // the call frames that were active, innermost first
+    Backtrace { error: Box<RuntimeError>, frames: Vec<String> },
+}
+
+impl Display for RuntimeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RuntimeError::StackUnderflow { stack, needed, available: 0 } if *needed == 1 => {
+                write!(f, "stack underflow: attempted to pop from empty stack {stack}")
+            }
+            RuntimeError::StackUnderflow { stack, needed, available } => {
+                write!(f, "stack underflow: attempted to pop {needed} values from stack {stack}, which has {available}")
+            }
+            RuntimeError::UnknownInstruction(name) => write!(f, "unknown instruction `{name}`"),
+            RuntimeError::UnknownLabel(label) => write!(f, "unknown label `{label}`"),
+            RuntimeError::InvalidInt(value) => write!(f, "failed to convert value to int: `{value}`"),
+            RuntimeError::InvalidChar(code) => write!(f, "`{code}` is not a valid character"),
+            RuntimeError::Other(message) => write!(f, "{message}"),
+            RuntimeError::At { file, line, column, error } => write!(f, "{file}:{line}:{column}: {error}"),
+            RuntimeError::Backtrace { error, frames } => {
+                write!(f, "{error}")?;
+                for frame in frames {
+                    write!(f, "\n    {frame}")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+// a line of the expanded source and a column on it
+type Position = (usize, usize);
+
+#[derive(Debug, Default)]
+struct SourceMap {
+    // original file and line of each line of the expanded source
+    lines: Vec<(String, usize)>,
+    // line of the expanded source and column each token starts on
+    tokens: Vec<Position>,
+    // keys and values of `#meta` directives, in the order they first appear
+    meta: Vec<(String, String)>,
+    // every file read, with how deeply it is included and whether it closes a cycle
+    include_tree: Vec<(usize, String, bool)>,
+}
+
+impl SourceMap {
+    // a later `#meta` with the same key replaces the value
+    fn set_meta(&mut self, key: &str, value: &str) {
+        match self.meta.iter_mut().find(|(k, _)| k == key) {
+            Some((_, v)) => *v = value.to_string(),
+            None => self.meta.push((key.to_string(), value.to_string())),
+        }
+    }
+
+    // indented by include depth, in the order the files are read
+    fn print_include_tree(&self) {
+        for (depth, file, cycle) in &self.include_tree {
+            let indent = "  ".repeat(*depth);
+            match cycle {
+                true => println!("{indent}{file}  (circular include)"),
+                false => println!("{indent}{file}"),
+            }
+        }
+    }
+
+    fn print_info(&self) {
+        for (key, value) in &self.meta {
+            println!("{key}\t{value}");
+        }
+    }
+
+    // prints each range of expanded lines along with where it comes from
+    fn dump(&self) {
+        let mut start = 0;
+        while start < self.lines.len() {
+            let (file, first_line) = &self.lines[start];
+
+            let mut end = start + 1;
+            while end < self.lines.len()
+                && self.lines[end].0 == *file
+                && self.lines[end].1 == first_line + end - start
+            {
+                end += 1;
+            }
+
+            println!("{}-{} {file}:{first_line}-{}", start + 1, end, first_line + end - start - 1);
+            start = end;
+        }
+    }
+
+    // the original file, line and column of a token
+    fn locate(&self, pc: usize) -> Option<(&str, usize, usize)> {
+        let (line, column) = *self.tokens.get(pc)?;
+        let (file, line) = self.lines.get(line)?;
+        Some((file, *line, column))
+    }
+
+    // the error, along with where the token is if that is known
+    fn at(&self, pc: usize, error: RuntimeError) -> RuntimeError {
+        match self.locate(pc) {
+            Some((file, line, column)) => RuntimeError::At { file: file.to_string(), line, column, error: Box::new(error) },
+            None => error,
+        }
+    }
+}
+
+// preprocessor directives understood by resolve_includes
+const DIRECTIVES: &[&str] = &["#define", "#include", "#include_if_exists", "#meta", "#require_version"];
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+// dotted numbers, where missing trailing parts count as 0
+fn parse_version(version: &str) -> Option<Vec<u64>> {
+    version.split('.').map(|part| part.parse().ok()).collect()
+}
+
+fn version_satisfies(required: &[u64]) -> bool {
+    // a pre-release or build suffix like `-beta` or `+git` does not take part in the comparison
+    let release = VERSION.split(['-', '+']).next().unwrap_or(VERSION);
+    let current = parse_version(release).unwrap_or_default();
+    let len = current.len().max(required.len());
+    let pad = |version: &[u64]| (0..len).map(|index| version.get(index).copied().unwrap_or(0)).collect::<Vec<_>>();
+
+    pad(&current) >= pad(required)
+}
+
+// `including` holds the files whose includes are being resolved, outermost first, as their
+// canonical path and the name they were included by
+fn resolve_includes(
+    mut source_path: PathBuf,
+    source_map: &mut SourceMap,
+    capabilities: &Capabilities,
+    including: &mut Vec<(PathBuf, String)>,
+    // each #define so far, with its value and where it was defined
+    defines: &mut HashMap<String, (String, String)>,
+) -> Result<String, RuntimeError> {
+    let source = match fs::read_to_string(&source_path) {
+        Ok(source) => source,
+        Err(e) => return Err(RuntimeError::Other(format!("failed to read `{}`: {e}", source_path.display()))),
+    };
+    let file = source_path.display().to_string();
+
+    let canonical = fs::canonicalize(&source_path).unwrap_or_else(|_| source_path.clone());
+    let cycle_start = including.iter().position(|(path, _)| *path == canonical);
+    source_map.include_tree.push((including.len(), file.clone(), cycle_start.is_some()));
+    if let Some(start) = cycle_start {
+        let mut cycle: Vec<&str> = including[start..].iter().map(|(_, name)| name.as_str()).collect();
+        cycle.push(&file);
+        return Err(RuntimeError::Other(format!("circular include detected: {}", cycle.join(" -> "))));
+    }
+    including.push((canonical, file.clone()));
+
+    source_path.pop();
+    let dir = source_path;
+    let mut included_source = String::new();
+
+    for (index, line) in source.lines().enumerate() {
+        let (path, optional) = match (line.strip_prefix("#include "), line.strip_prefix("#include_if_exists ")) {
+            (Some(path), _) => (Some(path), false),
+            (_, Some(path)) => (Some(path), true),
+            _ => (None, false),
+        };
+
+        if let Some(path) = path {
+            if !capabilities.includes {
+                return Err(RuntimeError::Other(format!("`#include` is disabled, but {file}:{} includes `{path}`", index + 1)));
+            }
+
+            // relative to the including file, joining leaves absolute paths unchanged
+            let include = dir.join(PathBuf::from(path));
+            if optional && !include.exists() {
+                continue;
+            }
+
+            included_source.push_str(&resolve_includes(include, source_map, capabilities, including, defines)?);
+        } else if let Some(required) = line.strip_prefix("#require_version ") {
+            let required = required.trim();
+            match parse_version(required) {
+                Some(version) if version_satisfies(&version) => {}
+                Some(_) => {
+                    return Err(RuntimeError::Other(format!(
+                        "{file}:{}: requires kcats {required}, but this is kcats {VERSION}", index + 1
+                    )));
+                }
+                None => return Err(RuntimeError::Other(format!("{file}:{}: invalid version `{required}`", index + 1))),
+            }
+        } else if let Some(define) = line.strip_prefix("#define ") {
+            let site = format!("{file}:{}", index + 1);
+            let (name, value) = match define.trim().split_once(char::is_whitespace) {
+                Some((name, value)) => (name, value.trim()),
+                None => return Err(RuntimeError::Other(format!("{site}: `#define {}` is missing a value", define.trim()))),
+            };
+            if let Some((_, earlier)) = defines.get(name) {
+                return Err(RuntimeError::Other(format!("{site}: `{name}` is already defined at {earlier}")));
+            }
+            defines.insert(name.to_string(), (value.to_string(), site));
+        } else if let Some(meta) = line.strip_prefix("#meta ") {
+            let (key, value) = meta.trim().split_once(char::is_whitespace).unwrap_or((meta.trim(), ""));
+            source_map.set_meta(key, value.trim());
+        } else {
+            included_source.push_str(&substitute_defines(line, defines));
+            included_source.push('\n');
+            source_map.lines.push((file.clone(), index + 1));
+        }
+    }
+
+    including.pop();
+    Ok(included_source)
+}
+
+// replaces each name given to #define with its value, leaving string literals and
+// comments alone; a name is split off where a token would be
+fn substitute_defines(line: &str, defines: &HashMap<String, (String, String)>) -> String {
+    if defines.is_empty() {
+        return line.to_string();
+    }
+
+    let mut out = String::with_capacity(line.len());
+    let mut chars = line.char_indices().peekable();
+    while let Some((start, c)) = chars.next() {
+        match c {
+            '"' => {
+                out.push(c);
+                while let Some((_, c)) = chars.next() {
+                    out.push(c);
+                    match c {
+                        '\\' => out.extend(chars.next().map(|(_, c)| c)),
+                        '"' => break,
+                        _ => {}
+                    }
+                }
+            }
+            '/' if line[start..].starts_with("//") => {
+                out.push_str(&line[start..]);
+                break;
+            }
+            '/' if line[start..].starts_with("/*") => match line[start + 2..].find("*/") {
+                Some(offset) => {
+                    let end = start + 2 + offset + 2;
+                    out.push_str(&line[start..end]);
+                    while chars.next_if(|&(index, _)| index < end).is_some() {}
+                }
+                None => {
+                    out.push_str(&line[start..]);
+                    break;
+                }
+            },
+            ' ' | '\t' | '(' | ')' => out.push(c),
+            _ => {
+                let mut end = start + c.len_utf8();
+                while let Some(&(index, c)) = chars.peek() {
+                    if matches!(c, ' ' | '\t' | '"' | '(' | ')') {
+                        break;
+                    }
+                    end = index + c.len_utf8();
+                    chars.next();
+                }
+
+                let word = &line[start..end];
+                match defines.get(word) {
+                    Some((value, _)) => out.push_str(value),
+                    None => out.push_str(word),
+                }
+            }
+        }
+    }
+
+    out
+}
+
+// keeps track of the current line while lexing
+struct Cursor<'a> {
+    chars: std::iter::Peekable<std::str::Chars<'a>>,
+    line: usize,
+    // of the last character read, starting at 1
+    column: usize,
+}
+
+impl Cursor<'_> {
+    fn peek(&mut self) -> Option<char> {
+        self.chars.peek().copied()
+    }
+}
+
+impl Iterator for Cursor<'_> {
+    type Item = char;
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.chars.next();
+        if c == Some('\n') {
+            self.line += 1;
+            self.column = 0;
+        } else {
+            self.column += 1;
+        }
+        c
+    }
+}
+
+// also returns the line and column each token starts on
+fn lex(src: String) -> Result<(Vec<Token>, Vec<Position>), RuntimeError> {
+    let mut src = Cursor { chars: src.chars().peekable(), line: 0, column: 0 };
+    let mut tokens = vec![];
+    let mut positions = vec![];
+    while let Some(c) = src.next() {
+        let position = (src.line, src.column);
+        match c {
+            '!' if src.peek() != Some('=') => tokens.push(Token::Bang),
+            '(' => tokens.push(Token::LParen),
+            ')' => tokens.push(Token::RParen),
+            '/' if src.peek() == Some('/') => {
+                loop {
+                    if let Some('\n') | None = src.next() {
+                        break;
+                    }
+                }
+            },
+            // block comments don't nest, so the first `*/` ends one
+            '/' if src.peek() == Some('*') => {
+                src.next();
+                loop { match src.next() {
+                    Some('*') if src.peek() == Some('/') => {
+                        src.next();
+                        break;
+                    }
+                    Some(_) => (),
+                    None => return Err(RuntimeError::Other("unfinished block comment".to_string())),
+                }}
+            },
+            '"' => {
+                let mut acc = String::new();
+                loop { match src.next() {
+                    Some('"') => break,
+                    Some('\\') => acc.push(unescape(src.next())?),
+                    Some(c) => acc.push(c),
+                    None => return Err(RuntimeError::Other("unfinished string literal".to_string())),
+                }}
+
+                tokens.push(Token::Str(acc));
+            }
+            // a `-` only starts a literal right before a digit, so `-` and `->` stay instructions
+            '0'..='9' | '-' if c != '-' || matches!(src.peek(), Some('0'..='9')) => {
+                let mut acc = String::new();
+                acc.push(c);
+                while let Some(c @ '0'..='9') = src.peek() {
+                    acc.push(c);
+                    src.next();
+                }
+
+                match acc.parse() {
+                    Ok(value) => tokens.push(Token::Int(value)),
+                    Err(_) => return Err(RuntimeError::Other(format!("integer literal `{acc}` is out of range"))),
+                }
+            },
+            ' ' | '\n' | '\t' => (),
+            c => {
+                let mut acc = String::new();
+                acc.push(c);
+                // the character that ends a name is left for the next token, so
+                // `(0 1 -)` still closes its parenthesis
+                loop { match src.peek() {
+                    Some(' ' | '\n' | '\t' | '"' | '(' | ')') | None => break,
+                    Some(c) => {
+                        acc.push(c);
+                        src.next();
+                    }
+                }}
+                tokens.push(Token::Ident(acc));
+            }
+        }
+        positions.resize(tokens.len(), position);
+    }
+
+    Ok((tokens, positions))
+}
+
+// the character after a backslash in a string literal
+fn unescape(escaped: Option<char>) -> Result<char, RuntimeError> {
+    match escaped {
+        Some('n') => Ok('\n'),
+        Some('t') => Ok('\t'),
+        Some('r') => Ok('\r'),
+        Some('0') => Ok('\0'),
+        Some('\\') => Ok('\\'),
+        Some('"') => Ok('"'),
+        Some(c) => Err(RuntimeError::Other(format!("unknown escape `\\{c}` in string literal"))),
+        None => Err(RuntimeError::Other("unfinished string literal".to_string())),
+    }
+}
+
+
+// what a program may access outside of its own state
+#[derive(Debug, Clone)]
+pub struct Capabilities {
+    // reading other files through `#include`; the program's own file is always read
+    pub includes: bool,
+    // reading and writing files from the program, off unless --allow-fs is given
+    pub files: bool,
+    // instructions that fail as soon as they run
+    pub disabled_instructions: Vec<String>,
+}
+
+impl Default for Capabilities {
+    fn default() -> Self {
+        Capabilities {
+            includes: true,
+            files: false,
+            disabled_instructions: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct Options {
+    pub capabilities: Capabilities,
+    // everything after `--` on the command line, for the program itself
+    pub args: Vec<String>,
+    // not a command line flag, used when the output has to be checked
+    pub capture_output: bool,
+    // not a command line flag either, set by the command line so that library callers
+    // don't get warnings on their stderr
+    pub warnings: bool,
+    pub assert_empty_at_exit: bool,
+    pub color_diff: bool,
+    pub output_buffering: Buffering,
+    pub completions: bool,
+    pub deterministic: bool,
+    pub dump_metrics: bool,
+    pub dump_source_map: bool,
+    pub echo_input: bool,
+    pub entry: Option<String>,
+    pub ensure_final_newline: bool,
+    pub debug: bool,
+    pub summary_on_error: bool,
+    pub sanitize_output: bool,
+    pub summary_size: Option<usize>,
+    pub info: bool,
+    pub repl: bool,
+    pub repl_history: Option<PathBuf>,
+    pub include_graph: bool,
+    pub fail_fast: bool,
+    pub version: bool,
+    pub list_instructions: bool,
+    pub list_labels: bool,
+    pub json_input: bool,
+    pub lossy_utf8_input: bool,
+    pub input_timeout: Option<u64>,
+    pub source_from_stdin: bool,
+    pub trace: bool,
+    pub trace_filter: Option<Vec<String>>,
+    pub trace_stack_diff: bool,
+    pub trace_depth: Option<usize>,
+    pub self_test: bool,
+    pub pretty_stacks: bool,
+    pub require_entry: bool,
+    pub max_call_depth: Option<usize>,
+    pub max_depth_per_stack: Option<usize>,
+    pub max_instructions: Option<u64>,
+    pub max_parens_depth: Option<usize>,
+    pub max_stacks: Option<usize>,
+    pub max_string_len: Option<usize>,
+    pub max_value_size: Option<usize>,
+    pub max_values: Option<usize>,
+    pub hot_labels_threshold: Option<f64>,
+    pub profile: bool,
+}
+
+impl Options {
+    // without a filter everything is traced; otherwise only instructions and labels
+    // whose name is in the filter; a label name matches its definition and bare jumps to it,
+    // and lands_on covers the jumps to it through goto_if, call and the like
+    fn traces(&self, token: &Token) -> bool {
+        match (&self.trace_filter, token) {
+            (None, _) => true,
+            (Some(filter), Token::Ident(instr)) => {
+                let name = instr.strip_prefix(':').unwrap_or(instr);
+                filter.iter().any(|entry| entry == name)
+            }
+            (Some(_), _) => false,
+        }
+    }
+
+    // whether a jump that landed on the token is traced because of its label
+    fn lands_on(&self, token: Option<&Token>) -> bool {
+        match (&self.trace_filter, token) {
+            (Some(filter), Some(Token::Ident(instr))) => match instr.strip_prefix(':') {
+                Some(label) => filter.iter().any(|entry| entry == label),
+                None => false,
+            },
+            _ => false,
+        }
+    }
+
+    // programs start at token 0 unless an entry is given or required,
+    // --require-entry alone requires `main`
+    fn entry_label(&self) -> Option<&str> {
+        match (&self.entry, self.require_entry) {
+            (Some(entry), _) => Some(entry),
+            (None, true) => Some("main"),
+            (None, false) => None,
+        }
+    }
+}
+
+// runs a program that doesn't come from a file, so it can't include anything
+pub fn run_source(name: &str, source: &str, options: Options) -> Result<Finished, RuntimeError> {
+    let (tokens, lines) = lex(source.to_string())?;
+    let source_map = SourceMap {
+        lines: (1..=source.lines().count()).map(|line| (name.to_string(), line)).collect(),
+        tokens: lines,
+        ..SourceMap::default()
+    };
+
+    interpret(tokens, source_map, options)
+}
+
+// returns the exit code
+pub fn run_file(source_path: PathBuf, options: Options) -> Result<i32, RuntimeError> {
+    let mut source_map = SourceMap::default();
+    let source = resolve_includes(source_path, &mut source_map, &options.capabilities, &mut Vec::new(), &mut HashMap::new());
+
+    // printed even when an include fails, since that is when it helps most
+    if options.include_graph {
+        source_map.print_include_tree();
+        return source.map(|_| 0);
+    }
+    let source = source?;
+    let (tokens, lines) = lex(source)?;
+    source_map.tokens = lines;
+
+    if options.info {
+        source_map.print_info();
+        return Ok(0);
+    }
+
+    if options.dump_source_map {
+        source_map.dump();
+        return Ok(0);
+    }
+
+    if options.list_labels {
+        list_labels(&tokens, &source_map)?;
+        return Ok(0);
+    }
+
+    Ok(interpret(tokens, source_map, options)?.exit_code)
+}
+
+// the whole of stdin is the program, so `input` and the like have nothing left to read;
+// returns the exit code
+pub fn run_stdin(mut options: Options) -> Result<i32, RuntimeError> {
+    use std::io::Read;
+
+    let mut source = String::new();
+    if let Err(e) = std::io::stdin().read_to_string(&mut source) {
+        return Err(RuntimeError::Other(format!("failed to read the program from stdin: {e}")));
+    }
+    options.source_from_stdin = true;
+
+    Ok(run_source("<stdin>", &source, options)?.exit_code)
+}
+
+/// Runs a program with the default options, returning everything it printed.
+/// The program can't include other files, and failing with `die` is an error.
+pub fn run(source: &str) -> Result<String, RuntimeError> {
+    let options = Options { capture_output: true, ..Options::default() };
+
+    let finished = run_source("<source>", source, options)?;
+    match finished.exit_code {
+        0 => Ok(finished.output),
+        code => Err(RuntimeError::Other(format!("exited with code {code}"))),
+    }
+}
+
+/// Runs an already lexed program, so that other frontends can generate tokens directly.
+pub fn run_tokens(tokens: Vec<Token>) -> Result<(), RuntimeError> {
+    interpret(tokens, SourceMap::default(), Options::default())?;
+    Ok(())
+}
+
+/// Compares output line by line, marking expected lines with `-` and actual lines with `+`,
+/// in red and green when `color` is set.
+pub fn render_diff(expected: &str, actual: &str, color: bool) -> String {
+    let (red, green, reset) = if color { ("\x1b[31m", "\x1b[32m", "\x1b[0m") } else { ("", "", "") };
+
+    let expected: Vec<_> = expected.lines().collect();
+    let actual: Vec<_> = actual.lines().collect();
+
+    let mut out = String::new();
+    for index in 0..expected.len().max(actual.len()) {
+        match (expected.get(index), actual.get(index)) {
+            (Some(x), Some(y)) if x == y => out.push_str(&format!("  {x}\n")),
+            (x, y) => {
+                if let Some(x) = x {
+                    out.push_str(&format!("{red}- {x}{reset}\n"));
+                }
+                if let Some(y) = y {
+                    out.push_str(&format!("{green}+ {y}{reset}\n"));
+                }
+            }
+        }
+    }
+
+    out
+}
+
+const REPL_HISTORY_SIZE: usize = 1000;
+
+/// Lines entered in the REPL, kept in a file between sessions.
+pub struct History {
+    path: PathBuf,
+    lines: Vec<String>,
+}
+
+impl History {
+    /// A history file that doesn't exist yet is empty.
+    pub fn load(path: PathBuf) -> Result<Self, RuntimeError> {
+        let lines = match fs::read_to_string(&path) {
+            Ok(contents) => contents.lines().map(str::to_string).collect(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(RuntimeError::Other(format!("failed to read `{}`: {e}", path.display()))),
+        };
+
+        let mut history = History { path, lines };
+        history.truncate();
+        Ok(history)
+    }
+
+    /// `$XDG_CONFIG_HOME/kcats/history`, or `~/.config/kcats/history`.
+    pub fn default_path() -> Option<PathBuf> {
+        let config = match std::env::var_os("XDG_CONFIG_HOME") {
+            Some(config) if !config.is_empty() => PathBuf::from(config),
+            _ => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+        };
+        Some(config.join("kcats").join("history"))
+    }
+
+    /// Blank lines and repeats of the previous line are left out.
+    pub fn push(&mut self, line: &str) {
+        if line.trim().is_empty() || self.lines.last().map(String::as_str) == Some(line) {
+            return;
+        }
+
+        self.lines.push(line.to_string());
+        self.truncate();
+    }
+
+    pub fn lines(&self) -> &[String] {
+        &self.lines
+    }
+
+    pub fn save(&self) -> Result<(), RuntimeError> {
+        if let Some(dir) = self.path.parent() {
+            if let Err(e) = fs::create_dir_all(dir) {
+                return Err(RuntimeError::Other(format!("failed to create `{}`: {e}", dir.display())));
+            }
+        }
+
+        let mut contents = self.lines.join("\n");
+        contents.push('\n');
+        match fs::write(&self.path, contents) {
+            Ok(()) => Ok(()),
+            Err(e) => Err(RuntimeError::Other(format!("failed to write `{}`: {e}", self.path.display()))),
+        }
+    }
+
+    // keeps the latest lines
+    fn truncate(&mut self) {
+        let excess = self.lines.len().saturating_sub(REPL_HISTORY_SIZE);
+        self.lines.drain(..excess);
+    }
+}
+
+// the terminal, without line buffering and echo while a line is being edited; the earlier
+// settings come back when this is dropped
+struct RawMode {
+    // from `stty -g`
+    saved: String,
+}
+
+impl RawMode {
+    fn new() -> Option<Self> {
+        let saved = stty(&["-g"])?;
+        stty(&["-icanon", "-echo", "-isig", "min", "1"])?;
+        Some(RawMode { saved: saved.trim().to_string() })
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        stty(&[&self.saved]);
+    }
+}
+
+// stty works on the terminal it gets as stdin, which is inherited from kcats
+fn stty(args: &[&str]) -> Option<String> {
+    use std::process::{Command, Stdio};
+
+    let output = Command::new("stty").args(args).stdin(Stdio::inherit()).stderr(Stdio::null()).output().ok()?;
+    output.status.success().then(|| String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+// reads a line typed at a terminal after the prompt, where up and down go through the
+// history and backspace deletes; None at the end of input, from ctrl-d on an empty line
+fn read_edited_line(history: &[String], lossy: bool) -> Result<Option<String>, RuntimeError> {
+    use std::io::{Read, Write};
+
+    let Some(_raw) = RawMode::new() else {
+        return Input::Stdin.read_line(lossy);
+    };
+    let mut stdin = std::io::stdin().lock();
+    let mut read_byte = || {
+        let mut byte = [0];
+        match stdin.read(&mut byte) {
+            Ok(0) => Ok(None),
+            Ok(_) => Ok(Some(byte[0])),
+            Err(e) => Err(RuntimeError::Other(format!("failed to read input: {e}"))),
+        }
+    };
+    let redraw = |line: &[u8]| eprint!("\r\x1b[K> {}", String::from_utf8_lossy(line));
+
+    let mut line = Vec::new();
+    // how many entries back in the history the line is, and what was typed before going there
+    let mut back = 0;
+    let mut typed = Vec::new();
+    loop {
+        let Some(byte) = read_byte()? else {
+            return Ok(None);
+        };
+        match byte {
+            b'\r' | b'\n' => {
+                eprintln!();
+                return decode_input(line, lossy).map(Some);
+            }
+            // ctrl-d
+            4 if line.is_empty() => return Ok(None),
+            // ctrl-c drops the line
+            3 => {
+                eprint!("^C\n> ");
+                line.clear();
+                back = 0;
+            }
+            // backspace, removing a whole character
+            8 | 127 => {
+                while let Some(byte) = line.pop() {
+                    if byte & 0b1100_0000 != 0b1000_0000 {
+                        break;
+                    }
+                }
+                redraw(&line);
+            }
+            // escape sequences, of which only up and down do anything
+            0x1b => {
+                if read_byte()? != Some(b'[') {
+                    continue;
+                }
+                let mut last = None;
+                while let Some(byte) = read_byte()? {
+                    last = Some(byte);
+                    if (0x40..=0x7e).contains(&byte) {
+                        break;
+                    }
+                }
+
+                match last {
+                    Some(b'A') if back < history.len() => {
+                        if back == 0 {
+                            typed = std::mem::take(&mut line);
+                        }
+                        back += 1;
+                        line = history[history.len() - back].clone().into_bytes();
+                    }
+                    Some(b'B') if back > 0 => {
+                        back -= 1;
+                        line = match back {
+                            0 => typed.clone(),
+                            _ => history[history.len() - back].clone().into_bytes(),
+                        };
+                    }
+                    _ => continue,
+                }
+                redraw(&line);
+            }
+            // other control characters
+            0..=0x1f => {}
+            byte => {
+                line.push(byte);
+                let _ = std::io::stderr().write_all(&[byte]);
+            }
+        }
+    }
+}
+
+/// Reads and runs one line at a time, keeping the stacks and labels between lines.
+/// The prompt and the current stack after each line go to stderr, so stdout only
+/// holds what the program prints.
+pub fn repl(options: Options) -> Result<(), RuntimeError> {
+    let mut history = match options.repl_history.clone().or_else(History::default_path) {
+        Some(path) => Some(History::load(path)?),
+        None => None,
+    };
+
+    let mut tokens = Vec::new();
+    let input = Input::new(&options);
+    let mut state = State::new(0, Labels::default(), SourceMap::default(), input, None, options);
+    // lines are only edited at a terminal, so piped input is read as it is
+    let edited = {
+        use std::io::IsTerminal;
+        matches!(state.input, Input::Stdin) && std::io::stdin().is_terminal() && std::io::stderr().is_terminal()
+    };
+
+    // counts every line read, so locations in errors match what was typed
+    let mut entries = 0;
+    while state.halted.is_none() {
+        state.output.flush();
+        eprint!("> ");
+        let line = match (edited, &history) {
+            (true, Some(history)) => read_edited_line(history.lines(), state.options.lossy_utf8_input)?,
+            (true, None) => read_edited_line(&[], state.options.lossy_utf8_input)?,
+            (false, _) => state.input.read_line(state.options.lossy_utf8_input)?,
+        };
+        let Some(line) = line else {
+            eprintln!();
+            break;
+        };
+        let line = line.trim_end_matches(['\n', '\r']);
+        if let Some(history) = &mut history {
+            history.push(line);
+        }
+
+        entries += 1;
+        let start = tokens.len();
+        match repl_load(line, entries, &mut tokens, &mut state) {
+            Ok(ops) => {
+                state.pc = start;
+                if let Err(e) = run_to_end(&mut state, &tokens, &ops) {
+                    eprintln!("error: {e}");
+                    state.call_stack.clear();
+                }
+                state.pc = tokens.len();
+            }
+            Err(e) => {
+                eprintln!("error: {e}");
+                tokens.truncate(start);
+                state.source_map.tokens.truncate(start);
+                state.source_map.lines.pop();
+            }
+        }
+        eprintln!("{:?}", state.stacks[state.current_stack]);
+    }
+
+    state.output.flush();
+    match history {
+        Some(history) => history.save(),
+        None => Ok(()),
+    }
+}
+
+// adds the line to the program, returning the ops of the whole program
+fn repl_load(line: &str, number: usize, tokens: &mut Vec<Token>, state: &mut State) -> Result<Vec<Op>, RuntimeError> {
+    let index = state.source_map.lines.len();
+    state.source_map.lines.push(("<repl>".to_string(), number));
+
+    let (line_tokens, positions) = lex(line.to_string())?;
+    state.source_map.tokens.extend(positions.into_iter().map(|(_, column)| (index, column)));
+    // checked on its own, so an unmatched parenthesis doesn't stay around for the next lines
+    let max_depth = state.options.max_parens_depth.unwrap_or(DEFAULT_MAX_PARENS_DEPTH);
+    if let Err(e) = check_parens(&line_tokens, tokens.len(), &state.source_map, max_depth) {
+        state.source_map.tokens.truncate(tokens.len());
+        return Err(e);
+    }
+    tokens.extend(line_tokens);
+
+    let labels = scan_labels(tokens, &state.source_map)?;
+    let ops = compile(tokens, &labels, &state.options.capabilities, &state.source_map)?;
+    state.labels = labels;
+    state.program_len = tokens.len();
+    Ok(ops)
+}
+
+// label definitions, in the order they appear in the source
+#[derive(Debug, Default)]
+struct Labels {
+    positions: HashMap<String, usize>,
+    definitions: Vec<(String, usize)>,
+}
+
+impl Labels {
+    // returns the pc of the earlier definition if the label already has one
+    fn define(&mut self, label: String, pc: usize) -> Result<(), usize> {
+        if let Some(&earlier) = self.positions.get(&label) {
+            return Err(earlier);
+        }
+
+        self.positions.insert(label.clone(), pc);
+        self.definitions.push((label, pc));
+        Ok(())
+    }
+
+    fn get(&self, label: &str) -> Option<&usize> {
+        self.positions.get(label)
+    }
+
+    // in source order
+    fn iter(&self) -> impl Iterator<Item = (&str, usize)> {
+        self.definitions.iter().map(|(label, pc)| (label.as_str(), *pc))
+    }
+}
+
+fn scan_labels(tokens: &[Token], source_map: &SourceMap) -> Result<Labels, RuntimeError> {
+    let mut labels = Labels::default();
+
+    for (pc, token) in tokens.iter().enumerate() {
+        if let Token::Ident(instr) = token {
+            if let Some(label) = instr.strip_prefix(':') {
+                if let Err(earlier) = labels.define(label.to_string(), pc) {
+                    let site = |pc| match source_map.locate(pc) {
+                        Some((file, line, column)) => format!("{file}:{line}:{column}"),
+                        None => format!("token {pc}"),
+                    };
+                    return Err(RuntimeError::Other(format!(
+                        "label `{label}` is defined twice, at {} and at {}", site(earlier), site(pc)
+                    )));
+                }
+            }
+        }
+    }
+
+    Ok(labels)
+}
+
+// parentheses only group, but have to match, without nesting deeper than --max-parens-depth;
+// `first_pc` is the pc of the first token
+fn check_parens(tokens: &[Token], first_pc: usize, source_map: &SourceMap, max_depth: usize) -> Result<(), RuntimeError> {
+    let mut open = Vec::new();
+
+    for (pc, token) in (first_pc..).zip(tokens) {
+        match token {
+            Token::LParen => {
+                if open.len() >= max_depth {
+                    let error = format!("parentheses are nested deeper than the maximum depth of {max_depth}");
+                    return Err(source_map.at(pc, RuntimeError::Other(error)));
+                }
+                open.push(pc);
+            }
+            Token::RParen if open.pop().is_none() => {
+                return Err(source_map.at(pc, RuntimeError::Other("unmatched `)`".to_string())));
+            }
+            _ => {}
+        }
+    }
+
+    match open.first() {
+        Some(&pc) => Err(source_map.at(pc, RuntimeError::Other("unmatched `(`".to_string()))),
+        None => Ok(()),
+    }
+}
+
+pub fn list_instructions() {
+    let mut names: Vec<_> = INSTRUCTIONS.iter().map(|(name, _)| *name).collect();
+    names.sort();
+
+    for name in names {
+        println!("{name}");
+    }
+}
+
+pub fn is_instruction(name: &str) -> bool {
+    INSTRUCTIONS.iter().any(|(instruction, _)| *instruction == name)
+}
+
+// one word per line, for shell completion scripts: instructions, directives, then the
+// command-line options given by the frontend
+pub fn print_completions(flags: &[&str]) {
+    let mut names: Vec<_> = INSTRUCTIONS.iter().map(|(name, _)| *name).collect();
+    names.sort();
+
+    for name in names.iter().chain(DIRECTIVES).chain(flags) {
+        println!("{name}");
+    }
+}
+
+// in order of definition
+fn list_labels(tokens: &[Token], source_map: &SourceMap) -> Result<(), RuntimeError> {
+    for (label, pc) in scan_labels(tokens, source_map)?.iter() {
+        match source_map.locate(pc) {
+            Some((file, line, _)) => println!("{label}\t{file}:{line}"),
+            None => println!("{label}"),
+        }
+    }
+    Ok(())
+}
+
+fn read_json_input(input: &mut Input, lossy: bool) -> Result<Json, RuntimeError> {
+    Json::parse(&decode_input(input.read_to_end()?, lossy)?)
+        .map_err(|e| RuntimeError::Other(format!("--json-input: stdin is not valid JSON: {e}")))
+}
+
+// how a run ended, the output is only kept if it was captured
+pub struct Finished {
+    pub exit_code: i32,
+    pub output: String,
+}
+
+// token indices move whenever an include above them grows or shrinks, so a hard-coded
+// `jump` target silently lands somewhere else; labels and `!` don't have that problem
+fn warn_raw_jumps(tokens: &[Token], source_map: &SourceMap) {
+    for (pc, pair) in tokens.windows(2).enumerate() {
+        let [Token::Int(target), Token::Ident(instr)] = pair else {
+            continue;
+        };
+        if instr != "jump" && instr != "jump_if" {
+            continue;
+        }
+
+        let location = match source_map.locate(pc) {
+            Some((file, line, column)) => format!("{file}:{line}:{column}: "),
+            None => String::new(),
+        };
+        eprintln!(
+            "warning: {location}`{instr}` to the raw token index {target} breaks when includes change; \
+             jump to a label, or to an index saved by `!`"
+        );
+    }
+}
+
+fn interpret(tokens: Vec<Token>, source_map: SourceMap, options: Options) -> Result<Finished, RuntimeError> {
+    if options.warnings {
+        warn_raw_jumps(&tokens, &source_map);
+    }
+    let labels = scan_labels(&tokens, &source_map)?;
+    check_parens(&tokens, 0, &source_map, options.max_parens_depth.unwrap_or(DEFAULT_MAX_PARENS_DEPTH))?;
+    let ops = compile(&tokens, &labels, &options.capabilities, &source_map)?;
+
+    let mut input = Input::new(&options);
+    let json_input = match options.json_input {
+        true => Some(read_json_input(&mut input, options.lossy_utf8_input)?),
+        false => None,
+    };
+
+    let mut state = State::new(tokens.len(), labels, source_map, input, json_input, options);
+
+    if let Some(entry) = state.options.entry_label() {
+        state.pc = match state.labels.get(entry) {
+            Some(pc) => *pc,
+            None if state.options.require_entry => {
+                return Err(RuntimeError::Other(format!("no entry label `{entry}`, required by --require-entry")));
+            }
+            None => return Err(RuntimeError::UnknownLabel(entry.to_string())),
+        };
+    }
+
+    run_to_end(&mut state, &tokens, &ops)?;
+
+    // what the program printed comes before the reports on stderr
+    state.output.flush();
+    if let Some(metrics) = &mut state.metrics {
+        metrics.record_memory(&state.stacks);
+    }
+    if let (Some(metrics), Some(profile)) = (&state.metrics, &state.profile) {
+        eprintln!("{}", metrics.to_json(state.steps, profile));
+    }
+
+    if let (Some(profile), true) = (&state.profile, state.options.profile) {
+        profile.print_counts();
+    }
+
+    if let (Some(profile), Some(threshold)) = (&state.profile, state.options.hot_labels_threshold) {
+        profile.print_hints(threshold);
+    }
+
+    if state.options.assert_empty_at_exit && state.halted.is_none() {
+        state.assert_empty()?;
+    }
+
+    if state.options.ensure_final_newline && !matches!(state.output.last_byte, None | Some(b'\n')) {
+        state.output.write("\n");
+    }
+
+    state.output.flush();
+    Ok(Finished {
+        exit_code: state.halted.unwrap_or(0),
+        output: state.output.captured.take().unwrap_or_default(),
+    })
+}
+
+// runs from the current pc until the program ends or stops itself
+fn run_to_end(state: &mut State, tokens: &[Token], ops: &[Op]) -> Result<(), RuntimeError> {
+    let mut debugger = state.options.debug.then(Debugger::new);
+    // --summary-on-error: the pc of the latest instructions, oldest first
+    let summary_size = state.options.summary_size.unwrap_or(DEFAULT_SUMMARY_SIZE);
+    let mut recent = state.options.summary_on_error.then(|| std::collections::VecDeque::with_capacity(summary_size));
+
+    while state.pc < tokens.len() && state.halted.is_none() {
+        if let Some(debugger) = &mut debugger {
+            debugger.before(state, tokens)?;
+            if state.halted.is_some() {
+                break;
+            }
+        }
+
+        let pc = state.pc;
+        if let Some(max) = state.options.max_instructions {
+            if state.steps >= max {
+                return Err(state.source_map.at(pc, RuntimeError::Other(format!("instruction limit of {max} exceeded"))));
+            }
+        }
+        let traced = state.options.traces(&tokens[pc]);
+        // with a filter, any instruction could turn out to jump to a filtered label
+        let before = ((traced || state.options.trace_filter.is_some()) && state.options.trace_stack_diff)
+            .then(|| (state.current_stack, state.stacks[state.current_stack].clone()));
+
+        if let Some(recent) = &mut recent {
+            if recent.len() >= summary_size.max(1) {
+                recent.pop_front();
+            }
+            recent.push_back(pc);
+        }
+
+        let unwinding = state.unwinding.is_some();
+        if let Err(error) = execute(state, &ops[pc]).and_then(|()| state.check_value_limit()) {
+            // the first error, given back by `ret` once every deferred label ran
+            if unwinding && state.unwinding.is_none() {
+                return Err(error);
+            }
+
+            if let Some(recent) = &recent {
+                state.output.flush();
+                print_summary(recent, tokens, &state.source_map);
+            }
+            let error = state.source_map.at(pc, error);
+            let error = match state.call_stack.is_empty() {
+                true => error,
+                false => RuntimeError::Backtrace { error: Box::new(error), frames: render_backtrace(state) },
+            };
+
+            // an error in a deferred label takes the place of the first one, and the other
+            // deferred labels still run
+            if unwinding || state.call_stack.iter().any(|frame| !frame.deferred.is_empty()) {
+                state.unwinding = Some(error);
+                state.unwind()?;
+                state.pc += 1;
+                continue;
+            }
+            return Err(error);
+        }
+        state.steps += 1;
+
+        let traced = traced || (state.pc != pc && state.options.lands_on(tokens.get(state.pc)));
+        let before = before.filter(|_| traced);
+        // stdout and stderr may be the same terminal, so pending output goes out before the trace
+        if traced && (state.options.trace || before.is_some()) {
+            state.output.flush();
+        }
+        if traced && state.options.trace {
+            eprintln!("{pc}: {}  {}", tokens[pc], render_stacks(&state.stacks, state.options.trace_depth));
+        }
+        if let Some((stack, before)) = before {
+            trace_stack_diff(pc, &tokens[pc], &before, &state.stacks[stack]);
+        }
+        if let Some(profile) = &mut state.profile {
+            profile.record(&tokens[pc], tokens.get(state.pc));
+        }
+        if let Some(metrics) = &mut state.metrics {
+            metrics.record(&state.stacks, state.steps);
+        }
+        state.pc += 1;
+    }
+
+    Ok(())
+}
+
+// Unicode names of the ASCII symbols and some common characters beyond ASCII, and the
+// formal aliases of a few control characters
+const CHAR_NAMES: &[(&str, char)] = &[
+    ("NULL", '\0'),
+    ("CHARACTER TABULATION", '\t'),
+    ("LINE FEED", '\n'),
+    ("CARRIAGE RETURN", '\r'),
+    ("ESCAPE", '\x1b'),
+    ("SPACE", ' '),
+    ("EXCLAMATION MARK", '!'),
+    ("QUOTATION MARK", '"'),
+    ("NUMBER SIGN", '#'),
+    ("DOLLAR SIGN", '$'),
+    ("PERCENT SIGN", '%'),
+    ("AMPERSAND", '&'),
+    ("APOSTROPHE", '\''),
+    ("LEFT PARENTHESIS", '('),
+    ("RIGHT PARENTHESIS", ')'),
+    ("ASTERISK", '*'),
+    ("PLUS SIGN", '+'),
+    ("COMMA", ','),
+    ("HYPHEN-MINUS", '-'),
+    ("FULL STOP", '.'),
+    ("SOLIDUS", '/'),
+    ("COLON", ':'),
+    ("SEMICOLON", ';'),
+    ("LESS-THAN SIGN", '<'),
+    ("EQUALS SIGN", '='),
+    ("GREATER-THAN SIGN", '>'),
+    ("QUESTION MARK", '?'),
+    ("COMMERCIAL AT", '@'),
+    ("LEFT SQUARE BRACKET", '['),
+    ("REVERSE SOLIDUS", '\\'),
+    ("RIGHT SQUARE BRACKET", ']'),
+    ("CIRCUMFLEX ACCENT", '^'),
+    ("LOW LINE", '_'),
+    ("GRAVE ACCENT", '`'),
+    ("LEFT CURLY BRACKET", '{'),
+    ("VERTICAL LINE", '|'),
+    ("RIGHT CURLY BRACKET", '}'),
+    ("TILDE", '~'),
+    ("NO-BREAK SPACE", '\u{a0}'),
+    ("SECTION SIGN", '§'),
+    ("COPYRIGHT SIGN", '©'),
+    ("REGISTERED SIGN", '®'),
+    ("DEGREE SIGN", '°'),
+    ("PLUS-MINUS SIGN", '±'),
+    ("MICRO SIGN", 'µ'),
+    ("MULTIPLICATION SIGN", '×'),
+    ("DIVISION SIGN", '÷'),
+    ("EN DASH", '–'),
+    ("EM DASH", '—'),
+    ("LEFT SINGLE QUOTATION MARK", '‘'),
+    ("RIGHT SINGLE QUOTATION MARK", '’'),
+    ("LEFT DOUBLE QUOTATION MARK", '“'),
+    ("RIGHT DOUBLE QUOTATION MARK", '”'),
+    ("BULLET", '•'),
+    ("HORIZONTAL ELLIPSIS", '…'),
+    ("EURO SIGN", '€'),
+    ("LEFTWARDS ARROW", '←'),
+    ("UPWARDS ARROW", '↑'),
+    ("RIGHTWARDS ARROW", '→'),
+    ("DOWNWARDS ARROW", '↓'),
+    ("INFINITY", '∞'),
+    ("REPLACEMENT CHARACTER", '\u{fffd}'),
+];
+
+const DIGIT_NAMES: [&str; 10] = ["ZERO", "ONE", "TWO", "THREE", "FOUR", "FIVE", "SIX", "SEVEN", "EIGHT", "NINE"];
+
+// in code point order, from alpha; capital final sigma is unassigned
+const GREEK_LETTER_NAMES: [&str; 25] = [
+    "ALPHA", "BETA", "GAMMA", "DELTA", "EPSILON", "ZETA", "ETA", "THETA", "IOTA", "KAPPA", "LAMDA", "MU", "NU",
+    "XI", "OMICRON", "PI", "RHO", "FINAL SIGMA", "SIGMA", "TAU", "UPSILON", "PHI", "CHI", "PSI", "OMEGA",
+];
+
+fn char_by_name(name: &str) -> Option<char> {
+    if let Some(&(_, c)) = CHAR_NAMES.iter().find(|(known, _)| *known == name) {
+        return Some(c);
+    }
+
+    let single_letter = |letter: &str| match letter.as_bytes() {
+        [c @ b'A'..=b'Z'] => Some(*c),
+        _ => None,
+    };
+    if let Some(letter) = name.strip_prefix("LATIN CAPITAL LETTER ") {
+        return single_letter(letter).map(char::from);
+    }
+    if let Some(letter) = name.strip_prefix("LATIN SMALL LETTER ") {
+        return single_letter(letter).map(|c| char::from(c.to_ascii_lowercase()));
+    }
+    if let Some(digit) = name.strip_prefix("DIGIT ") {
+        let index = DIGIT_NAMES.iter().position(|known| *known == digit)?;
+        return Some(char::from(b'0' + index as u8));
+    }
+
+    let (first, letter) = match name.strip_prefix("GREEK SMALL LETTER ") {
+        Some(letter) => (0x3b1, letter),
+        None => (0x391, name.strip_prefix("GREEK CAPITAL LETTER ")?),
+    };
+    if first == 0x391 && letter == "FINAL SIGMA" {
+        return None;
+    }
+    let index = GREEK_LETTER_NAMES.iter().position(|known| *known == letter)?;
+    char::from_u32(first + index as u32)
+}
+
+// code point ranges, both ends included, of characters that take no column: combining
+// marks, zero-width spaces and joiners, and variation selectors
+const ZERO_WIDTH_CHARS: &[(u32, u32)] = &[
+    (0x0300, 0x036f),
+    (0x0483, 0x0489),
+    (0x0591, 0x05bd),
+    (0x0610, 0x061a),
+    (0x064b, 0x065f),
+    (0x1ab0, 0x1aff),
+    (0x1dc0, 0x1dff),
+    (0x200b, 0x200f),
+    (0x2060, 0x2064),
+    (0x20d0, 0x20ff),
+    (0xfe00, 0xfe0f),
+    (0xfe20, 0xfe2f),
+    (0xfeff, 0xfeff),
+    (0xe0100, 0xe01ef),
+];
+
+// code point ranges of characters that take two columns: the CJK, Hangul and fullwidth
+// blocks, and the main emoji blocks
+const WIDE_CHARS: &[(u32, u32)] = &[
+    (0x1100, 0x115f),
+    (0x2e80, 0x303e),
+    (0x3041, 0x33ff),
+    (0x3400, 0x4dbf),
+    (0x4e00, 0x9fff),
+    (0xa000, 0xa4cf),
+    (0xac00, 0xd7a3),
+    (0xf900, 0xfaff),
+    (0xfe30, 0xfe4f),
+    (0xff00, 0xff60),
+    (0xffe0, 0xffe6),
+    (0x1f300, 0x1f64f),
+    (0x1f900, 0x1f9ff),
+    (0x20000, 0x2fffd),
+    (0x30000, 0x3fffd),
+];
+
+// close to what terminals do, from the tables above rather than the full Unicode data;
+// control characters take no column
+fn char_width(c: char) -> usize {
+    let code = c as u32;
+    let within = |ranges: &[(u32, u32)]| ranges.iter().any(|&(start, end)| (start..=end).contains(&code));
+
+    if c.is_control() || within(ZERO_WIDTH_CHARS) {
+        0
+    } else if within(WIDE_CHARS) {
+        2
+    } else {
+        1
+    }
+}
+
+fn single_char_delimiter(delimiter: String, instruction: &str) -> Result<char, RuntimeError> {
+    let mut chars = delimiter.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => Ok(c),
+        _ => Err(RuntimeError::Other(format!("`{instruction}` needs a single character delimiter, not {delimiter:?}"))),
+    }
+}
+
+// shifting by the width of an int or more has no result that makes sense
+fn pop_shift_amount(state: &mut State, name: &str) -> Result<u32, RuntimeError> {
+    let amount = state.pop_int()?;
+
+    match u32::try_from(amount) {
+        Ok(amount) if amount < INT_TYPE::BITS => Ok(amount),
+        _ => Err(RuntimeError::Other(format!("shift amount {amount} in `{name}` is out of range, expected 0 to {}", INT_TYPE::BITS - 1))),
+    }
+}
+
+type InstructionFn = fn(&mut State) -> Result<(), RuntimeError>;
+
+// every built-in instruction, by name
+const INSTRUCTIONS: &[(&str, InstructionFn)] = &[
+    ("+", i_add),
+    ("-", i_sub),
+    ("*", i_mul),
+    ("/", i_div),
+    ("%", i_mod),
+    ("==", i_eq),
+    ("!=", i_neq),
+    ("=>", i_copy_right),
+    ("<=", i_copy_left),
+    (".", i_get),
+    ("->", State::switch_to_right_stack),
+    ("<-", State::switch_to_left_stack),
+    ("abs", i_abs),
+    ("and", i_and),
+    ("argv", i_argv),
+    ("assert", i_assert),
+    ("band", i_band),
+    ("bnot", i_bnot),
+    ("bor", i_bor),
+    ("bxor", i_bxor),
+    ("call", i_call),
+    ("case", i_case),
+    ("char_by_name", i_char_by_name),
+    ("char_range", i_char_range),
+    ("checkpoint", i_checkpoint),
+    ("chr", i_chr),
+    ("clear", i_clear),
+    ("commit", i_commit),
+    ("concat", i_concat),
+    ("count_begin", i_count_begin),
+    ("count_end", i_count_end),
+    ("debug", i_debug),
+    ("defer", i_defer),
+    ("depth", i_depth),
+    ("die", i_die),
+    ("discard", i_discard),
+    ("display_width", i_display_width),
+    ("dup", i_dup),
+    ("dup_to", i_dup_to),
+    ("dup_under", i_dup_under),
+    ("empty", i_empty),
+    ("explode", i_explode),
+    ("fill", i_fill),
+    ("flatten", i_flatten),
+    ("for_each_line", i_for_each_line),
+    ("from_bytes", i_from_bytes),
+    ("implode", i_implode),
+    ("input", i_input),
+    ("input_eof", i_input_eof),
+    ("insert", i_insert),
+    ("is_int", i_is_int),
+    ("int_or_default", i_int_or_default),
+    ("ge", i_ge),
+    ("goto_if", i_goto_if),
+    ("goto_indexed", i_goto_indexed),
+    ("goto_stack", i_goto_stack),
+    ("gt", i_gt),
+    ("halt", i_halt),
+    ("home", i_home),
+    ("json_get", i_json_get),
+    ("join", i_join),
+    ("jump", i_jump),
+    ("jump_if", i_jump_if),
+    ("le", i_le),
+    ("len", i_len),
+    ("lt", i_lt),
+    ("max", i_max),
+    ("max_n", i_max_n),
+    ("memo_call", i_memo_call),
+    ("min", i_min),
+    ("min_n", i_min_n),
+    ("move_if", i_move_if),
+    ("normalize_nl", i_normalize_nl),
+    ("not", i_not),
+    ("or", i_or),
+    ("ord", i_ord),
+    ("over", i_over),
+    ("pack", i_pack),
+    ("parse_num", i_parse_num),
+    ("peek", i_peek),
+    ("peek_stack", i_peek_stack),
+    ("pop", i_pop),
+    ("pow", i_pow),
+    ("pop_stack_ptr", i_pop_stack_ptr),
+    ("print", i_print),
+    ("product_n", i_product_n),
+    ("push_stack_ptr", i_push_stack_ptr),
+    ("rand", i_rand),
+    ("rand_str", i_rand_str),
+    ("range", i_range),
+    ("read_all", i_read_all),
+    ("read_byte", i_read_byte),
+    ("read_file", i_read_file),
+    ("remove_at", i_remove_at),
+    ("repeat_char", i_repeat_char),
+    ("replace_first", i_replace_first),
+    ("ret", i_ret),
+    ("reverse", i_reverse),
+    ("rollback", i_rollback),
+    ("rot", i_rot),
+    ("rotate_str", i_rotate_str),
+    ("scmp", i_scmp),
+    ("seed", i_seed),
+    ("set_depth", i_set_depth),
+    ("shl", i_shl),
+    ("shr", i_shr),
+    ("spill", i_spill),
+    ("split", i_split),
+    ("src_file", i_src_file),
+    ("src_line", i_src_line),
+    ("stack_contains", i_stack_contains),
+    ("stack_index", i_stack_index),
+    ("stacks_equal", i_stacks_equal),
+    ("substr", i_substr),
+    ("sum_n", i_sum_n),
+    ("swap", i_swap),
+    ("swap_case", i_swap_case),
+    ("tag", i_tag),
+    ("to_bytes", i_to_bytes),
+    ("to_int", i_to_int),
+    ("to_str", i_to_str),
+    ("todo", i_todo),
+    ("unreachable", i_unreachable),
+    ("unpack", i_unpack),
+    ("uri_decode", i_uri_decode),
+    ("uri_encode", i_uri_encode),
+    ("write_file", i_write_file),
+];
+
+// a token with its instruction or label looked up ahead of time, so running it
+// doesn't search for names
+#[derive(Debug)]
+enum Op {
+    // parentheses and label definitions
+    Nop,
+    SavePc,
+    PushInt(INT_TYPE),
+    PushStr(String),
+    Instruction(InstructionFn),
+    // still an error only once it runs, like before the instruction was disabled
+    Disabled(&'static str),
+    // a bare label name, which jumps to the label
+    Goto(usize),
+}
+
+// one op per token, so a pc means the same in both; unknown names are reported here
+// instead of when they first run
+fn compile(tokens: &[Token], labels: &Labels, capabilities: &Capabilities, source_map: &SourceMap) -> Result<Vec<Op>, RuntimeError> {
+    let mut ops = Vec::with_capacity(tokens.len());
+
+    for (pc, token) in tokens.iter().enumerate() {
+        let op = match token {
+            Token::LParen | Token::RParen => Op::Nop,
+            Token::Bang => Op::SavePc,
+            Token::Int(i) => Op::PushInt(*i),
+            Token::Str(s) => Op::PushStr(s.clone()),
+            Token::Ident(instr) => match INSTRUCTIONS.iter().find(|(name, _)| name == instr) {
+                Some((name, _)) if capabilities.disabled_instructions.iter().any(|disabled| disabled == name) => Op::Disabled(name),
+                Some((_, instruction)) => Op::Instruction(*instruction),
+                None if instr.starts_with(':') => Op::Nop,
+                None => match labels.get(instr) {
+                    Some(target) => Op::Goto(*target),
+                    None => return Err(source_map.at(pc, RuntimeError::UnknownInstruction(instr.clone()))),
+                },
+            },
+        };
+        ops.push(op);
+    }
+
+    Ok(ops)
+}
+
+fn execute(state: &mut State, op: &Op) -> Result<(), RuntimeError> {
+    match *op {
+        Op::Nop => Ok(()),
+        Op::SavePc => i_save_pc(state),
+        Op::PushInt(i) => state.push_int(i),
+        Op::PushStr(ref s) => state.push_string(s.clone()),
+        Op::Instruction(instruction) => instruction(state),
+        Op::Disabled(name) => Err(RuntimeError::Other(format!("instruction `{name}` is disabled"))),
+        Op::Goto(pc) => {
+            state.pc = pc;
+            Ok(())
+        }
+    }
+}
+
+// the most lines of frames a backtrace shows, half from each end of the call stack
+const BACKTRACE_LINES: usize = 20;
+
+// each active call frame and where it was called from, innermost first; a recursive call
+// repeating the same frame is shown once, so runaway recursion doesn't print every frame
+fn render_backtrace(state: &State) -> Vec<String> {
+    let mut runs: Vec<(&Frame, usize)> = Vec::new();
+    for frame in state.call_stack.iter().rev() {
+        match runs.last_mut() {
+            Some((last, count)) if last.label == frame.label && last.return_pc == frame.return_pc && last.kind == frame.kind => {
+                *count += 1;
+            }
+            _ => runs.push((frame, 1)),
+        }
+    }
+
+    let render = |&(frame, count): &(&Frame, usize)| {
+        let caller = match frame.kind {
+            FrameKind::Call => "called",
+            FrameKind::ForEachLine => "called by for_each_line",
+            FrameKind::Memo(_) => "called by memo_call",
+            FrameKind::Deferred => "deferred, run",
+        };
+        let mut line = match state.source_map.locate(frame.return_pc) {
+            Some((file, line, column)) => format!("in `{}`, {caller} at {file}:{line}:{column}", frame.label),
+            None => format!("in `{}`, {caller} at pc {}", frame.label, frame.return_pc),
+        };
+        if count > 1 {
+            let s = if count == 2 { "" } else { "s" };
+            line.push_str(&format!("\n    ... {} more frame{s} like the one above", count - 1));
+        }
+        line
+    };
+
+    if runs.len() <= BACKTRACE_LINES {
+        return runs.iter().map(render).collect();
+    }
+    let (inner, rest) = runs.split_at(BACKTRACE_LINES / 2);
+    let (hidden, outer) = rest.split_at(rest.len() - BACKTRACE_LINES / 2);
+    let hidden: usize = hidden.iter().map(|(_, count)| count).sum();
+
+    inner.iter().map(render)
+        .chain([format!("... {hidden} more frames")])
+        .chain(outer.iter().map(render))
+        .collect()
+}
+
+const DEFAULT_SUMMARY_SIZE: usize = 20;
+
+fn print_summary(recent: &std::collections::VecDeque<usize>, tokens: &[Token], source_map: &SourceMap) {
+    eprintln!("last {} instructions, up to the one that failed:", recent.len());
+    for &pc in recent {
+        match source_map.locate(pc) {
+            Some((file, line, column)) => eprintln!("    {pc}: {}  at {file}:{line}:{column}", tokens[pc]),
+            None => eprintln!("    {pc}: {}", tokens[pc]),
+        }
+    }
+}
+
+// --debug: pauses before tokens and reads commands from stdin, which it shares with the program
+#[derive(Debug)]
+struct Debugger {
+    // label names and the pc of the first token after the label, which is where both
+    // falling through and jumping to it continue
+    breakpoints: Vec<(String, usize)>,
+    stepping: bool,
+}
+
+const DEBUGGER_HELP: &str = "\
+commands:
+    s, step          run the next token
+    c, continue      run until a breakpoint
+    b, break LABEL   pause when LABEL is reached
+    d, delete LABEL  remove the breakpoint on LABEL
+    l, list          list the breakpoints
+    p, print         print the state
+    q, quit          stop the program";
+
+impl Debugger {
+    // starts paused, so breakpoints can be set before anything runs
+    fn new() -> Self {
+        Debugger { breakpoints: Vec::new(), stepping: true }
+    }
+
+    fn before(&mut self, state: &mut State, tokens: &[Token]) -> Result<(), RuntimeError> {
+        let pc = state.pc;
+        if !self.stepping {
+            match self.breakpoints.iter().find(|(_, at)| *at == pc) {
+                Some((label, _)) => eprintln!("breakpoint `{label}` reached"),
+                None => return Ok(()),
+            }
+        }
+        self.stepping = true;
+
+        state.output.flush();
+        loop {
+            eprint!("{pc}: {}  (debug) ", tokens[pc]);
+            let Some(line) = state.input.read_line(state.options.lossy_utf8_input)? else {
+                // nothing left to read commands from, so let the program finish
+                eprintln!();
+                self.stepping = false;
+                self.breakpoints.clear();
+                return Ok(());
+            };
+
+            let mut words = line.split_whitespace();
+            match (words.next(), words.next()) {
+                (None | Some("s" | "step"), None) => return Ok(()),
+                (Some("c" | "continue"), None) => {
+                    self.stepping = false;
+                    return Ok(());
+                }
+                (Some("b" | "break"), Some(label)) => match state.labels.get(label) {
+                    Some(at) => {
+                        self.breakpoints.retain(|(name, _)| name != label);
+                        self.breakpoints.push((label.to_string(), at + 1));
+                    }
+                    None => eprintln!("no label `{label}`"),
+                },
+                (Some("d" | "delete"), Some(label)) => self.breakpoints.retain(|(name, _)| name != label),
+                (Some("l" | "list"), None) => {
+                    for (label, at) in &self.breakpoints {
+                        eprintln!("{label}\t{at}");
+                    }
+                }
+                (Some("p" | "print"), None) => eprintln!("{state}"),
+                (Some("q" | "quit"), None) => {
+                    state.halted = Some(1);
+                    return Ok(());
+                }
+                _ => eprintln!("{DEBUGGER_HELP}"),
+            }
+        }
+    }
+}
+
+fn trace_stack_diff(pc: usize, token: &Token, before: &[Value], after: &[Value]) {
+    let common = before.iter()
+        .zip(after)
+        .take_while(|(x, y)| x == y)
+        .count();
+
+    eprintln!("{pc}: {token}  -{:?} +{:?}", &before[common..], &after[common..]);
+}
+
+#[derive(Debug)]
+struct State {
+    pc: usize,
+    // how many tokens the program has, the end of the range pcs live in
+    program_len: usize,
+    current_stack: usize,
+    stacks: Vec<Vec<Value>>,
+    named_stacks: HashMap<String, Vec<Value>>,
+    labels: Labels,
+    call_stack: Vec<Frame>,
+    // the error that is waiting for the deferred labels to run
+    unwinding: Option<RuntimeError>,
+    // the exit code, once the program stopped itself
+    halted: Option<i32>,
+    // instructions executed so far
+    steps: u64,
+    counters: Vec<u64>,
+    checkpoints: Vec<Snapshot>,
+    // current stacks saved by push_stack_ptr
+    stack_ptrs: Vec<usize>,
+    // results of memo_call, by label and argument
+    memo: HashMap<(String, String), Value>,
+    rng: Rng,
+    input: Input,
+    // whether the latest `input` found the end of input
+    input_ended: bool,
+    output: Output,
+    profile: Option<Profile>,
+    metrics: Option<Metrics>,
+    // stdin, parsed up front with --json-input
+    json_input: Option<Json>,
+    source_map: SourceMap,
+    options: Options,
+}
+
+// every stack as it was at some point, the rest of the state is left out
+#[derive(Debug, Clone)]
+struct Snapshot {
+    current_stack: usize,
+    stacks: Vec<Vec<Value>>,
+    named_stacks: HashMap<String, Vec<Value>>,
+}
+
+#[derive(Debug)]
+struct Metrics {
+    started: std::time::Instant,
+    // across all stacks
+    peak_depth: usize,
+    peak_memory: usize,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics { started: std::time::Instant::now(), peak_depth: 0, peak_memory: 0 }
+    }
+}
+
+impl Metrics {
+    // summing the size of every value after each instruction would make a run quadratic, so
+    // memory is only measured every VALUE_LIMIT_INTERVAL instructions and at the end, and a
+    // peak in between can be missed; the depth is cheap enough to count every time
+    fn record(&mut self, stacks: &[Vec<Value>], steps: u64) {
+        let depth = stacks.iter().map(Vec::len).sum();
+        self.peak_depth = self.peak_depth.max(depth);
+
+        if steps.is_multiple_of(VALUE_LIMIT_INTERVAL) {
+            self.record_memory(stacks);
+        }
+    }
+
+    fn record_memory(&mut self, stacks: &[Vec<Value>]) {
+        let memory = stacks.iter().flatten().map(Value::len).sum();
+        self.peak_memory = self.peak_memory.max(memory);
+    }
+
+    fn to_json(&self, instructions: u64, profile: &Profile) -> String {
+        let mut label_hits: Vec<_> = profile.label_hits.iter().collect();
+        label_hits.sort();
+
+        let label_hits: Vec<_> = label_hits.into_iter()
+            .map(|(label, hits)| format!("{}: {hits}", json_string(label)))
+            .collect();
+
+        format!(
+            "{{\"instructions\": {instructions}, \"peak_depth\": {}, \"peak_memory_bytes\": {}, \"wall_time_ms\": {:.3}, \"label_hits\": {{{}}}}}",
+            self.peak_depth,
+            self.peak_memory,
+            self.started.elapsed().as_secs_f64() * 1000.0,
+            label_hits.join(", "),
+        )
+    }
+}
+
+fn json_string(string: &str) -> String {
+    let mut out = String::from("\"");
+
+    for c in string.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+
+    out.push('"');
+    out
+}
+
+#[derive(Debug, Default)]
+struct Profile {
+    instructions: u64,
+    current_label: Option<String>,
+    label_hits: HashMap<String, u64>,
+    label_instructions: HashMap<String, u64>,
+    // by name, for identifiers and `!`; literals aren't counted
+    instruction_counts: HashMap<String, u64>,
+}
+
+impl Profile {
+    // called after each instruction with the token control is about to continue from
+    fn record(&mut self, executed: &Token, next: Option<&Token>) {
+        self.instructions += 1;
+        let name = match executed {
+            Token::Ident(instr) => Some(instr.as_str()),
+            Token::Bang => Some("!"),
+            _ => None,
+        };
+        if let Some(name) = name {
+            match self.instruction_counts.get_mut(name) {
+                Some(count) => *count += 1,
+                None => {
+                    self.instruction_counts.insert(name.to_string(), 1);
+                }
+            }
+        }
+        if let Some(label) = &self.current_label {
+            *self.label_instructions.entry(label.clone()).or_default() += 1;
+        }
+
+        if let Some(Token::Ident(instr)) = next {
+            if let Some(label) = instr.strip_prefix(':') {
+                *self.label_hits.entry(label.to_string()).or_default() += 1;
+                self.current_label = Some(label.to_string());
+            }
+        }
+    }
+
+    // for --profile: the most executed first, then label definitions by how often they were reached,
+    // ties in alphabetical order
+    fn print_counts(&self) {
+        let mut counts: Vec<_> = self.instruction_counts.iter()
+            .filter(|(name, _)| !name.starts_with(':'))
+            .map(|(name, count)| (name.clone(), *count))
+            .chain(self.label_hits.iter().map(|(label, hits)| (format!(":{label}"), *hits)))
+            .collect();
+        counts.sort_by(|(a_name, a), (b_name, b)| b.cmp(a).then(a_name.cmp(b_name)));
+
+        let counts: Vec<_> = counts.iter().map(|(name, count)| format!("{name}: {count}")).collect();
+        eprintln!("profile: {}", counts.join(", "));
+    }
+
+    fn print_hints(&self, threshold: f64) {
+        let mut labels: Vec<_> = self.label_instructions.iter().collect();
+        labels.sort_by(|(a_label, a), (b_label, b)| b.cmp(a).then(a_label.cmp(b_label)));
+
+        for (label, instructions) in labels {
+            let share = *instructions as f64 / self.instructions as f64 * 100.0;
+            if share < threshold {
+                break;
+            }
+
+            let hits = self.label_hits.get(label).copied().unwrap_or_default();
+            if hits > 1 {
+                eprintln!(
+                    "hint: label `:{label}` ran {hits} times and accounts for {share:.1}% of executed instructions \
+                    — consider hoisting invariant work out of it",
+                );
+            } else {
+                eprintln!(
+                    "hint: label `:{label}` accounts for {share:.1}% of executed instructions \
+                    — consider optimizing it first",
+                );
+            }
+        }
+    }
+}
+
+// when written output reaches stdout:
+// `line` after every newline, `block` once a few kilobytes are pending, `none` after every write;
+// pending output is always flushed before reading input, before kcats writes to stderr and
+// when the run ends
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum Buffering {
+    #[default]
+    Line,
+    Block,
+    None,
+}
+
+const OUTPUT_BLOCK_SIZE: usize = 8192;
+
+#[derive(Debug)]
+struct Output {
+    last_byte: Option<u8>,
+    buffering: Buffering,
+    pending: String,
+    // written to instead of stdout when set
+    captured: Option<String>,
+    // --sanitize-output
+    sanitize: bool,
+}
+
+impl Output {
+    fn new(buffering: Buffering, capture: bool, sanitize: bool) -> Self {
+        Output { last_byte: None, buffering, pending: String::new(), captured: capture.then(String::new), sanitize }
+    }
+
+    fn write(&mut self, text: &str) {
+        if self.sanitize {
+            for c in text.chars() {
+                match c {
+                    '\n' | '\t' => self.pending.push(c),
+                    c if c.is_ascii_control() => self.pending.push_str(&format!("\\x{:02x}", c as u32)),
+                    c if c.is_control() => self.pending.push_str(&format!("\\u{{{:x}}}", c as u32)),
+                    c => self.pending.push(c),
+                }
+            }
+        } else {
+            self.pending.push_str(text);
+        }
+
+        let flush = match self.buffering {
+            Buffering::Line => text.contains('\n'),
+            Buffering::Block => self.pending.len() >= OUTPUT_BLOCK_SIZE,
+            Buffering::None => true,
+        };
+        if flush {
+            self.flush();
+        }
+
+        if let Some(&byte) = text.as_bytes().last() {
+            self.last_byte = Some(byte);
+        }
+    }
+
+    fn flush(&mut self) {
+        use std::io::{self, Write};
+
+        if self.pending.is_empty() {
+            return;
+        }
+
+        if let Some(captured) = &mut self.captured {
+            captured.push_str(&self.pending);
+        } else {
+            print!("{}", self.pending);
+            io::stdout().flush().unwrap();
+        }
+        self.pending.clear();
+    }
+}
+
+// so that a failing run still shows what it printed
+impl Drop for Output {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+// where the program reads from; with --input-timeout, stdin is read on a background thread
+// so that a read can stop waiting. That thread can't be interrupted, so after a timeout it
+// stays blocked on stdin until the process exits, and anything it reads later is lost
+#[derive(Debug)]
+enum Input {
+    Stdin,
+    // stdin was read as the program source
+    Taken,
+    Timed {
+        chunks: std::sync::mpsc::Receiver<std::io::Result<Vec<u8>>>,
+        pending: std::collections::VecDeque<u8>,
+        timeout: std::time::Duration,
+    },
+}
+
+impl Input {
+    fn new(options: &Options) -> Self {
+        match options.input_timeout {
+            _ if options.source_from_stdin => Input::Taken,
+            Some(ms) => Input::timed(std::io::stdin(), std::time::Duration::from_millis(ms)),
+            None => Input::Stdin,
+        }
+    }
+
+    fn check_not_taken(&self) -> Result<(), RuntimeError> {
+        match self {
+            Input::Taken => Err(RuntimeError::Other("there is no input to read, stdin was read as the program".to_string())),
+            _ => Ok(()),
+        }
+    }
+
+    fn timed(mut reader: impl std::io::Read + Send + 'static, timeout: std::time::Duration) -> Self {
+        let (sender, chunks) = std::sync::mpsc::channel();
+
+        std::thread::spawn(move || loop {
+            let mut chunk = vec![0; 4096];
+            match reader.read(&mut chunk) {
+                Ok(0) => break,
+                Ok(len) => {
+                    chunk.truncate(len);
+                    if sender.send(Ok(chunk)).is_err() {
+                        break;
+                    }
+                }
+                Err(e) => {
+                    let _ = sender.send(Err(e));
+                    break;
+                }
+            }
+        });
+
+        Input::Timed { chunks, pending: Default::default(), timeout }
+    }
+
+    // waits for more input, returning false at the end of input
+    fn fill(&mut self) -> Result<bool, RuntimeError> {
+        use std::sync::mpsc::RecvTimeoutError;
+
+        let Input::Timed { chunks, pending, timeout } = self else {
+            return Ok(false);
+        };
+        match chunks.recv_timeout(*timeout) {
+            Ok(Ok(chunk)) => {
+                pending.extend(chunk);
+                Ok(true)
+            }
+            Ok(Err(e)) => Err(RuntimeError::Other(format!("failed to read input: {e}"))),
+            Err(RecvTimeoutError::Timeout) => {
+                Err(RuntimeError::Other(format!("input timeout: nothing was read within {} ms", timeout.as_millis())))
+            }
+            Err(RecvTimeoutError::Disconnected) => Ok(false),
+        }
+    }
+
+    // the next line with its line ending, or None at the end of input
+    fn read_line(&mut self, lossy: bool) -> Result<Option<String>, RuntimeError> {
+        use std::io::BufRead;
+
+        self.check_not_taken()?;
+        if let Input::Stdin = self {
+            let mut line = Vec::new();
+            return match std::io::stdin().lock().read_until(b'\n', &mut line) {
+                Ok(0) => Ok(None),
+                Ok(_) => decode_input(line, lossy).map(Some),
+                Err(e) => Err(RuntimeError::Other(format!("failed to read input: {e}"))),
+            };
+        }
+
+        loop {
+            let Input::Timed { pending, .. } = self else { unreachable!() };
+            if let Some(end) = pending.iter().position(|&byte| byte == b'\n') {
+                let line: Vec<u8> = pending.drain(..=end).collect();
+                return decode_input(line, lossy).map(Some);
+            }
+
+            if !self.fill()? {
+                let Input::Timed { pending, .. } = self else { unreachable!() };
+                if pending.is_empty() {
+                    return Ok(None);
+                }
+                return decode_input(pending.drain(..).collect(), lossy).map(Some);
+            }
+        }
+    }
+
+    // None at the end of input
+    fn read_byte(&mut self) -> Result<Option<u8>, RuntimeError> {
+        use std::io::Read;
+
+        self.check_not_taken()?;
+
+        if let Input::Stdin = self {
+            let mut byte = [0];
+            return match std::io::stdin().read(&mut byte) {
+                Ok(0) => Ok(None),
+                Ok(_) => Ok(Some(byte[0])),
+                Err(e) => Err(RuntimeError::Other(format!("failed to read input: {e}"))),
+            };
+        }
+
+        loop {
+            let Input::Timed { pending, .. } = self else { unreachable!() };
+            if let Some(byte) = pending.pop_front() {
+                return Ok(Some(byte));
+            }
+            if !self.fill()? {
+                return Ok(None);
+            }
+        }
+    }
+
+    fn read_to_end(&mut self) -> Result<Vec<u8>, RuntimeError> {
+        use std::io::Read;
+
+        self.check_not_taken()?;
+
+        if let Input::Stdin = self {
+            let mut bytes = Vec::new();
+            return match std::io::stdin().read_to_end(&mut bytes) {
+                Ok(_) => Ok(bytes),
+                Err(e) => Err(RuntimeError::Other(format!("failed to read input: {e}"))),
+            };
+        }
+
+        while self.fill()? {}
+        let Input::Timed { pending, .. } = self else { unreachable!() };
+        Ok(pending.drain(..).collect())
+    }
+}
+
+fn decode_input(bytes: Vec<u8>, lossy: bool) -> Result<String, RuntimeError> {
+    if lossy {
+        Ok(String::from_utf8_lossy(&bytes).into_owned())
+    } else {
+        String::from_utf8(bytes).map_err(|e| {
+            RuntimeError::Other(format!("input is not valid UTF-8 at byte {}", e.utf8_error().valid_up_to()))
+        })
+    }
+}
+
+const DEFAULT_MAX_CALL_DEPTH: usize = 100_000;
+const DEFAULT_MAX_PARENS_DEPTH: usize = 1_000;
+const DEFAULT_MAX_STACKS: usize = 100_000;
+// above what `range` can push at once
+const DEFAULT_MAX_VALUES: usize = 50_000_000;
+const VALUE_LIMIT_INTERVAL: u64 = 1024;
+
+// the most values `range` pushes at once
+const MAX_RANGE_LEN: u64 = 10_000_000;
+
+#[derive(Debug)]
+struct Frame {
+    return_pc: usize,
+    label: String,
+    kind: FrameKind,
+    // labels to run when this frame returns, last one first
+    deferred: Vec<String>,
+}
+
+#[derive(Debug, PartialEq)]
+enum FrameKind {
+    Call,
+    // calls the label again with the next line of input on each return
+    ForEachLine,
+    // remembers the value on top when returning, as the result for this argument
+    Memo(String),
+    // runs a label given to `defer`, then goes on returning the frame that deferred it
+    Deferred,
+}
+
+// splitmix64, small and good enough for toy programs
+#[derive(Debug)]
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        Rng(seed)
+    }
+
+    fn time_seed() -> u64 {
+        use std::time::{SystemTime, UNIX_EPOCH};
+
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+}
+
+// arrays and objects nest at most this deep, the parser recursing once per level
+const JSON_MAX_DEPTH: usize = 128;
+
+// just enough JSON for --json-input, numbers are kept as written
+#[derive(Debug)]
+enum Json {
+    Null,
+    Bool(bool),
+    Number(String),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    fn parse(src: &str) -> Result<Json, String> {
+        let mut chars = src.chars().peekable();
+        let value = Json::parse_value(&mut chars, 0)?;
+
+        Json::skip_whitespace(&mut chars);
+        match chars.next() {
+            None => Ok(value),
+            Some(c) => Err(format!("unexpected `{c}` after the value")),
+        }
+    }
+
+    fn skip_whitespace(chars: &mut std::iter::Peekable<std::str::Chars>) {
+        while chars.next_if(|c| matches!(c, ' ' | '\t' | '\n' | '\r')).is_some() {}
+    }
+
+    fn expect(chars: &mut std::iter::Peekable<std::str::Chars>, expected: char) -> Result<(), String> {
+        Json::skip_whitespace(chars);
+        match chars.next() {
+            Some(c) if c == expected => Ok(()),
+            Some(c) => Err(format!("expected `{expected}`, found `{c}`")),
+            None => Err(format!("expected `{expected}`, found the end of the input")),
+        }
+    }
+
+    // `depth` counts the arrays and objects around the value
+    fn parse_value(chars: &mut std::iter::Peekable<std::str::Chars>, depth: usize) -> Result<Json, String> {
+        Json::skip_whitespace(chars);
+
+        match chars.peek().copied() {
+            None => Err("unexpected end of the input".to_string()),
+            Some('[' | '{') if depth == JSON_MAX_DEPTH => {
+                Err(format!("arrays and objects are too deeply nested, more than {JSON_MAX_DEPTH} levels"))
+            }
+            Some('"') => Ok(Json::String(Json::parse_string(chars)?)),
+            Some('[') => {
+                chars.next();
+                let mut items = Vec::new();
+
+                Json::skip_whitespace(chars);
+                if chars.next_if_eq(&']').is_some() {
+                    return Ok(Json::Array(items));
+                }
+                loop {
+                    items.push(Json::parse_value(chars, depth + 1)?);
+
+                    Json::skip_whitespace(chars);
+                    match chars.next() {
+                        Some(',') => {}
+                        Some(']') => return Ok(Json::Array(items)),
+                        _ => return Err("expected `,` or `]` in an array".to_string()),
+                    }
+                }
+            }
+            Some('{') => {
+                chars.next();
+                let mut fields = Vec::new();
+
+                Json::skip_whitespace(chars);
+                if chars.next_if_eq(&'}').is_some() {
+                    return Ok(Json::Object(fields));
+                }
+                loop {
+                    Json::skip_whitespace(chars);
+                    if chars.peek() != Some(&'"') {
+                        return Err("expected a string key in an object".to_string());
+                    }
+                    let key = Json::parse_string(chars)?;
+                    Json::expect(chars, ':')?;
+                    fields.push((key, Json::parse_value(chars, depth + 1)?));
+
+                    Json::skip_whitespace(chars);
+                    match chars.next() {
+                        Some(',') => {}
+                        Some('}') => return Ok(Json::Object(fields)),
+                        _ => return Err("expected `,` or `}` in an object".to_string()),
+                    }
+                }
+            }
+            Some(c) if c == '-' || c.is_ascii_digit() => {
+                let mut number = String::new();
+                while let Some(c) = chars.next_if(|c| c.is_ascii_digit() || matches!(c, '-' | '+' | '.' | 'e' | 'E')) {
+                    number.push(c);
+                }
+
+                if number.parse::<f64>().is_err() {
+                    return Err(format!("invalid number `{number}`"));
+                }
+                Ok(Json::Number(number))
+            }
+            Some(_) => {
+                let mut word = String::new();
+                while let Some(c) = chars.next_if(|c| c.is_ascii_alphabetic()) {
+                    word.push(c);
+                }
+
+                match word.as_str() {
+                    "null" => Ok(Json::Null),
+                    "true" => Ok(Json::Bool(true)),
+                    "false" => Ok(Json::Bool(false)),
+                    _ => Err(format!("unexpected `{}`", chars.peek().map_or(word, |c| c.to_string()))),
+                }
+            }
+        }
+    }
+
+    fn parse_string(chars: &mut std::iter::Peekable<std::str::Chars>) -> Result<String, String> {
+        chars.next();
+        let mut string = String::new();
+
+        loop {
+            match chars.next() {
+                None => return Err("unterminated string".to_string()),
+                Some('"') => return Ok(string),
+                Some('\\') => match chars.next() {
+                    Some('"') => string.push('"'),
+                    Some('\\') => string.push('\\'),
+                    Some('/') => string.push('/'),
+                    Some('b') => string.push('\u{8}'),
+                    Some('f') => string.push('\u{c}'),
+                    Some('n') => string.push('\n'),
+                    Some('r') => string.push('\r'),
+                    Some('t') => string.push('\t'),
+                    Some('u') => {
+                        let hex: String = chars.by_ref().take(4).collect();
+                        let code = u32::from_str_radix(&hex, 16).map_err(|_| format!("invalid escape `\\u{hex}`"))?;
+                        // surrogate pairs are not worth it here
+                        string.push(char::from_u32(code).unwrap_or(char::REPLACEMENT_CHARACTER));
+                    }
+                    _ => return Err("invalid escape in a string".to_string()),
+                },
+                Some(c) => string.push(c),
+            }
+        }
+    }
+
+    // `a.b.0` looks up key `a`, then key `b`, then index 0, the empty path is the whole document
+    fn get(&self, path: &str) -> Option<&Json> {
+        if path.is_empty() {
+            return Some(self);
+        }
+
+        path.split('.').try_fold(self, |value, segment| match value {
+            Json::Object(fields) => fields.iter().rev().find(|(key, _)| key == segment).map(|(_, value)| value),
+            Json::Array(items) => segment.parse::<usize>().ok().and_then(|index| items.get(index)),
+            _ => None,
+        })
+    }
+
+    // strings are pushed without quotes and booleans as 1 or 0, anything else as JSON
+    fn to_value(&self) -> String {
+        match self {
+            Json::String(string) => string.clone(),
+            Json::Bool(b) => (*b as INT_TYPE).to_string(),
+            _ => self.to_string(),
+        }
+    }
+}
+
+fn write_json_string(f: &mut std::fmt::Formatter<'_>, string: &str) -> std::fmt::Result {
+    write!(f, "\"")?;
+    for c in string.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if c.is_control() => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{c}")?,
+        }
+    }
+    write!(f, "\"")
+}
+
+impl Display for Json {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(b) => write!(f, "{b}"),
+            Json::Number(number) => write!(f, "{number}"),
+            Json::String(string) => write_json_string(f, string),
+            Json::Array(items) => {
+                write!(f, "[")?;
+                for (index, item) in items.iter().enumerate() {
+                    if index > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{item}")?;
+                }
+                write!(f, "]")
+            }
+            Json::Object(fields) => {
+                write!(f, "{{")?;
+                for (index, (key, value)) in fields.iter().enumerate() {
+                    if index > 0 {
+                        write!(f, ",")?;
+                    }
+                    write_json_string(f, key)?;
+                    write!(f, ":{value}")?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+impl Display for State {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.options.pretty_stacks {
+            return write!(f, "{}", render_pretty(self));
+        }
+
+        write!(f, "{{ pc: {}, current_stack: {}, stacks: {} }}",
+            self.pc, self.current_stack, render_stacks(&self.stacks, self.options.trace_depth)
+        )
+    }
+}
+
+// like the debug representation, but with only the top `depth` values of each stack
+fn render_stacks(stacks: &[Vec<Value>], depth: Option<usize>) -> String {
+    let Some(depth) = depth else {
+        return format!("{stacks:?}");
+    };
+
+    let rendered: Vec<_> = stacks.iter()
+        .map(|stack| {
+            let hidden = stack.len().saturating_sub(depth);
+            let values = stack[hidden..].iter().map(|value| format!("{value:?}"));
+
+            let values: Vec<_> = (hidden > 0).then(|| "...".to_string()).into_iter().chain(values).collect();
+            format!("[{}]", values.join(", "))
+        })
+        .collect();
+
+    format!("[{}]", rendered.join(", "))
+}
+
+// one value per line, deepest first, with control characters escaped
+fn render_pretty(state: &State) -> String {
+    let mut out = format!("pc: {}", state.pc);
+
+    for (index, stack) in state.stacks.iter().enumerate() {
+        let marker = if index == state.current_stack { " (current)" } else { "" };
+        out.push_str(&format!("\nstack {index}{marker}:"));
+
+        if stack.is_empty() {
+            out.push_str("\n  (empty)");
+        }
+
+        let hidden = state.options.trace_depth.map_or(0, |depth| stack.len().saturating_sub(depth));
+        if hidden > 0 {
+            out.push_str(&format!("\n  ... ({hidden} more)"));
+        }
+        for value in &stack[hidden..] {
+            out.push_str(&format!("\n  \"{}\"", escape_value(&value.to_string())));
+            if let Some(tag) = value.tag() {
+                out.push_str(&format!(" #{tag}"));
+            }
+        }
+    }
+
+    out
+}
+
+fn escape_value(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+
+    for c in value.chars() {
+        match c {
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            '\r' => out.push_str("\\r"),
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if c.is_control() => out.push_str(&format!("\\u{{{:x}}}", c as u32)),
+            c => out.push(c),
+        }
+    }
+
+    out
+}
+
+impl Drop for State {
+    fn drop(&mut self) {
+        //eprintln!("Exiting state: {self}");
+    }
+}
+
+impl State {
+    fn new(
+        program_len: usize,
+        labels: Labels,
+        source_map: SourceMap,
+        input: Input,
+        json_input: Option<Json>,
+        options: Options,
+    ) -> Self {
+        State {
+            pc: 0,
+            program_len,
+            current_stack: 0,
+            stacks: vec![Vec::new()],
+            named_stacks: HashMap::new(),
+            labels,
+            call_stack: Vec::new(),
+            unwinding: None,
+            halted: None,
+            steps: 0,
+            counters: Vec::new(),
+            checkpoints: Vec::new(),
+            stack_ptrs: Vec::new(),
+            memo: HashMap::new(),
+            rng: Rng::new(if options.deterministic { 0 } else { Rng::time_seed() }),
+            input,
+            input_ended: false,
+            output: Output::new(options.output_buffering, options.capture_output, options.sanitize_output),
+            profile: (options.profile || options.hot_labels_threshold.is_some() || options.dump_metrics).then(Profile::default),
+            metrics: options.dump_metrics.then(Metrics::default),
+            json_input,
+            source_map,
+            options,
+        }
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            current_stack: self.current_stack,
+            stacks: self.stacks.clone(),
+            named_stacks: self.named_stacks.clone(),
+        }
+    }
+
+    fn restore(&mut self, snapshot: Snapshot) {
+        self.current_stack = snapshot.current_stack;
+        self.stacks = snapshot.stacks;
+        self.named_stacks = snapshot.named_stacks;
+    }
+
+    fn push_value(&mut self, value: Value) -> Result<(), RuntimeError> {
+        // a tagged string is as large as the string, ints are never large enough to count
+        let untagged = match &value {
+            Value::Tagged(value, _) => value,
+            value => value,
+        };
+        if let (Some(max), Value::Str(string)) = (self.options.max_value_size, untagged) {
+            if string.len() > max {
+                return Err(RuntimeError::Other(format!("value of {} bytes exceeds the maximum value size of {max} bytes", string.len())));
+            }
+        }
+
+        self.check_depth(self.current_stack, 1)?;
+        self.stacks[self.current_stack].push(value);
+        Ok(())
+    }
+
+    fn push_string(&mut self, value: String) -> Result<(), RuntimeError> {
+        self.push_value(Value::Str(value))
+    }
+
+    fn push_int(&mut self, value: INT_TYPE) -> Result<(), RuntimeError> {
+        self.check_depth(self.current_stack, 1)?;
+        self.stacks[self.current_stack].push(Value::Int(value));
+        Ok(())
+    }
+
+    // onto any stack, creating it if needed
+    fn push_to(&mut self, index: INT_TYPE, value: Value) -> Result<(), RuntimeError> {
+        // validates the index and creates the stack first
+        self.stack_mut(index)?;
+        self.check_depth(index as usize, 1)?;
+
+        self.stacks[index as usize].push(value);
+        Ok(())
+    }
+
+    // before `added` values go onto a stack
+    fn check_depth(&self, index: usize, added: usize) -> Result<(), RuntimeError> {
+        if let Some(max) = self.options.max_depth_per_stack {
+            if self.stacks[index].len() + added > max {
+                return Err(RuntimeError::Other(format!("stack {index} would exceed the maximum depth of {max} values")));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn pop_value(&mut self) -> Result<Value, RuntimeError> {
+        match self.stacks[self.current_stack].pop() {
+            Some(value) => Ok(value),
+            None => Err(RuntimeError::StackUnderflow { stack: self.current_stack, needed: 1, available: 0 }),
+        }
+    }
+
+    fn pop_string(&mut self) -> Result<String, RuntimeError> {
+        Ok(self.pop_value()?.into_string())
+    }
+
+    fn pop_int(&mut self) -> Result<INT_TYPE, RuntimeError> {
+        match self.pop_value()?.untagged() {
+            Value::Int(i) => Ok(i),
+            value => value.to_int().ok_or_else(|| RuntimeError::InvalidInt(value.into_string())),
+        }
+    }
+
+    fn assert_empty(&self) -> Result<(), RuntimeError> {
+        for (index, stack) in self.stacks.iter().enumerate() {
+            if !stack.is_empty() {
+                return Err(RuntimeError::Other(format!("stack {index} is not empty at exit: {stack:?}")));
+            }
+        }
+
+        // sorted, so the error is the same from one run to the next
+        let mut named: Vec<_> = self.named_stacks.iter().filter(|(_, stack)| !stack.is_empty()).collect();
+        named.sort_by_key(|(a, _)| *a);
+        if !named.is_empty() {
+            let named: Vec<_> = named.iter().map(|(name, stack)| format!("`{name}` {stack:?}")).collect();
+            return Err(RuntimeError::Other(format!("named stacks are not empty at exit: {}", named.join(", "))));
+        }
+
+        Ok(())
+    }
+
+    fn check_string_len(&self, len: usize) -> Result<(), RuntimeError> {
+        if let Some(max) = self.options.max_string_len {
+            if len > max {
+                return Err(RuntimeError::Other(format!("string of length {len} exceeds the maximum string length of {max}")));
+            }
+        }
+
+        Ok(())
+    }
+
+    // pops the top `count` values, deepest first
+    fn pop_values(&mut self, count: INT_TYPE) -> Result<Vec<Value>, RuntimeError> {
+        let stack = &mut self.stacks[self.current_stack];
+
+        if count < 0 {
+            return Err(RuntimeError::Other(format!("attempted to pop a negative number of values: {count}")));
+        }
+        if count as usize > stack.len() {
+            return Err(RuntimeError::StackUnderflow { stack: self.current_stack, needed: count as usize, available: stack.len() });
+        }
+
+        Ok(stack.split_off(stack.len() - count as usize))
+    }
+
+    fn pop_ints(&mut self, count: INT_TYPE) -> Result<Vec<INT_TYPE>, RuntimeError> {
+        self.pop_values(count)?
+            .into_iter()
+            .map(|value| match value.to_int() {
+                Some(i) => Ok(i),
+                None => Err(RuntimeError::InvalidInt(value.into_string())),
+            })
+            .collect()
+    }
+
+    fn push_frame(&mut self, frame: Frame) -> Result<(), RuntimeError> {
+        let max = self.options.max_call_depth.unwrap_or(DEFAULT_MAX_CALL_DEPTH);
+        if self.call_stack.len() >= max {
+            return Err(RuntimeError::Other(format!("call stack overflow: exceeded the maximum call depth of {max} while calling `{}`", frame.label)));
+        }
+
+        self.call_stack.push(frame);
+        Ok(())
+    }
+
+    // the owner goes back on the call stack, under the frame of the deferred label
+    fn run_deferred(&mut self, owner: Frame, label: String) -> Result<(), RuntimeError> {
+        let pc = self.label_pc(&label)?;
+        self.call_stack.push(owner);
+        self.push_frame(Frame { return_pc: self.pc, label, kind: FrameKind::Deferred, deferred: Vec::new() })?;
+        self.pc = pc;
+        Ok(())
+    }
+
+    // after an error, runs the next deferred label of the innermost frame that has one,
+    // and gives the error back once none are left
+    fn unwind(&mut self) -> Result<(), RuntimeError> {
+        while let Some(mut frame) = self.call_stack.pop() {
+            if let Some(label) = frame.deferred.pop() {
+                return self.run_deferred(frame, label);
+            }
+        }
+
+        Err(self.unwinding.take().unwrap())
+    }
+
+    // other stacks are created on demand, like when moving right
+    fn stack_mut(&mut self, index: INT_TYPE) -> Result<&mut Vec<Value>, RuntimeError> {
+        if index < 0 {
+            return Err(RuntimeError::Other(format!("attempted to access a negative stack index: {index}")));
+        }
+
+        let index = index as usize;
+        if index >= self.stacks.len() {
+            let max = self.options.max_stacks.unwrap_or(DEFAULT_MAX_STACKS);
+            if index >= max {
+                return Err(RuntimeError::Other(format!("attempted to create more than the maximum of {max} stacks")));
+            }
+            self.stacks.resize_with(index + 1, Vec::new);
+        }
+
+        Ok(&mut self.stacks[index])
+    }
+
+    // a pc popped by jump or jump_if, which has to be the index of a token
+    fn jump_target(&self, target: INT_TYPE) -> Result<usize, RuntimeError> {
+        match usize::try_from(target) {
+            Ok(pc) if pc < self.program_len => Ok(pc),
+            Ok(_) => Err(RuntimeError::Other(format!(
+                "jump target {target} is out of range (program has {} tokens)", self.program_len
+            ))),
+            Err(_) => Err(RuntimeError::Other(format!("jump target {target} is negative"))),
+        }
+    }
+
+    fn check_files_allowed(&self, instruction: &str) -> Result<(), RuntimeError> {
+        match self.options.capabilities.files {
+            true => Ok(()),
+            false => Err(RuntimeError::Other(format!("`{instruction}` is only available with --allow-fs"))),
+        }
+    }
+
+    fn label_pc(&self, label: &str) -> Result<usize, RuntimeError> {
+        match self.labels.get(label) {
+            Some(pc) => Ok(*pc),
+            None => Err(RuntimeError::UnknownLabel(label.to_string())),
+        }
+    }
+
+    // reads a line from stdin without its line ending, or None at the end of input
+    fn read_line(&mut self) -> Result<Option<String>, RuntimeError> {
+        self.output.flush();
+        let Some(mut line) = self.input.read_line(self.options.lossy_utf8_input)? else {
+            return Ok(None);
+        };
+
+        if line.ends_with('\n') {
+            line.pop();
+            if line.ends_with('\r') {
+                line.pop();
+            }
+        }
+        self.echo_input(&line);
+        Ok(Some(line))
+    }
+
+    fn echo_input(&mut self, line: &str) {
+        if self.options.echo_input {
+            self.output.write(line);
+            self.output.write("\n");
+        }
+    }
+
+    fn switch_to_left_stack(&mut self) -> Result<(), RuntimeError> {
+        if self.current_stack == 0 {
+            return Err(RuntimeError::Other("attempted to switch to the left stack from the leftmost stack".to_string()));
+        }
+
+        self.current_stack -= 1;
+        Ok(())
+    }
+
+    fn switch_to_right_stack(&mut self) -> Result<(), RuntimeError> {
+        if self.current_stack + 1 == self.stacks.len() {
+            let max = self.options.max_stacks.unwrap_or(DEFAULT_MAX_STACKS);
+            if self.stacks.len() >= max {
+                return Err(RuntimeError::Other(format!("attempted to create more than the maximum of {max} stacks")));
+            }
+            self.stacks.push(Vec::new());
+        }
+
+        self.current_stack += 1;
+        Ok(())
+    }
+
+    // counting every value on each push would slow every run down, so the total is only
+    // checked every VALUE_LIMIT_INTERVAL instructions and a run can briefly go past it
+    fn check_value_limit(&self) -> Result<(), RuntimeError> {
+        if !self.steps.is_multiple_of(VALUE_LIMIT_INTERVAL) {
+            return Ok(());
+        }
+
+        let max = self.options.max_values.unwrap_or(DEFAULT_MAX_VALUES);
+        let total: usize = self.stacks.iter().chain(self.named_stacks.values()).map(Vec::len).sum();
+        if total > max {
+            return Err(RuntimeError::Other(format!("the stacks hold {total} values, more than the maximum of {max}")));
+        }
+        Ok(())
+    }
+}
+
+// INSTRUCTIONS
+
+// $value abs
+fn i_abs(state: &mut State) -> Result<(), RuntimeError> {
+    let value = state.pop_int()?;
+
+    match value.checked_abs() {
+        Some(abs) => state.push_int(abs)?,
+        None => return Err(RuntimeError::Other(format!("integer overflow in `abs`: |{value}| doesn't fit in an int"))),
+    }
+    Ok(())
+}
+
+// $condition $message assert
+fn i_assert(state: &mut State) -> Result<(), RuntimeError> {
+    let message = state.pop_string()?;
+    let condition = state.pop_int()?;
+
+    if condition == 0 {
+        return Err(RuntimeError::Other(format!("assertion failed: {message}")))
+    }
+    Ok(())
+}
+
+// $x $y band
+fn i_band(state: &mut State) -> Result<(), RuntimeError> {
+    let y = state.pop_int()?;
+    let x = state.pop_int()?;
+
+    state.push_int(x & y)
+}
+
+// $x bnot
+fn i_bnot(state: &mut State) -> Result<(), RuntimeError> {
+    let x = state.pop_int()?;
+
+    state.push_int(!x)
+}
+
+// $x $y bor
+fn i_bor(state: &mut State) -> Result<(), RuntimeError> {
+    let y = state.pop_int()?;
+    let x = state.pop_int()?;
+
+    state.push_int(x | y)
+}
+
+// $x $y bxor
+fn i_bxor(state: &mut State) -> Result<(), RuntimeError> {
+    let y = state.pop_int()?;
+    let x = state.pop_int()?;
+
+    state.push_int(x ^ y)
+}
+
+// $x $y +
+fn i_add(state: &mut State) -> Result<(), RuntimeError> {
+    let y = state.pop_int()?;
+    let x = state.pop_int()?;
+
+    match x.checked_add(y) {
+        Some(result) => state.push_int(result),
+        None => Err(RuntimeError::Other(format!("integer overflow in `+`: {x} + {y} doesn't fit in an int"))),
+    }
+}
+
+// $a $b and
+fn i_and(state: &mut State) -> Result<(), RuntimeError> {
+    let b = state.pop_int()?;
+    let a = state.pop_int()?;
+
+    state.push_int((a != 0 && b != 0) as INT_TYPE)?;
+    Ok(())
+}
+
+// argv
+// pushes the arguments given after `--` on the command line, the first one deepest,
+// then how many there are
+fn i_argv(state: &mut State) -> Result<(), RuntimeError> {
+    let args = state.options.args.clone();
+
+    for arg in &args {
+        state.push_string(arg.clone())?;
+    }
+    state.push_int(args.len() as INT_TYPE)
+}
+
+// $label call
+// the return pc goes on the call stack, which the program's own stacks never see
+fn i_call(state: &mut State) -> Result<(), RuntimeError> {
+    let label = state.pop_string()?;
+
+    let pc = state.label_pc(&label)?;
+    state.push_frame(Frame { return_pc: state.pc, label, kind: FrameKind::Call, deferred: Vec::new() })?;
+    state.pc = pc;
+    Ok(())
+}
+
+// $value $pattern1 $label1 ... $patternN $labelN $n case
+// jumps to the label of the first pattern equal to the value,
+// or falls through to the next instruction if none match
+fn i_case(state: &mut State) -> Result<(), RuntimeError> {
+    let count = state.pop_int()?;
+    if count < 0 {
+        return Err(RuntimeError::Other(format!("attempted to match a case with a negative number of patterns: {count}")))
+    }
+
+    let arms = state.pop_values(count * 2)?;
+    let value = state.pop_value()?;
+
+    let target = arms.chunks(2).find(|arm| arm[0] == value).map(|arm| &arm[1]);
+    if let Some(label) = target {
+        state.pc = state.label_pc(&label.to_string())?;
+    }
+    Ok(())
+}
+
+// $name char_by_name
+// only knows the subset of names in CHAR_NAMES, plus Latin letters, digits and Greek letters
+fn i_char_by_name(state: &mut State) -> Result<(), RuntimeError> {
+    let name = state.pop_string()?;
+
+    match char_by_name(&name.to_ascii_uppercase()) {
+        Some(c) => state.push_string(c.to_string()),
+        None => Err(RuntimeError::Other(format!("unknown character name `{name}`"))),
+    }
+}
+
+// $start $end char_range
+// pushes every character from start to end, both included, then how many there are
+fn i_char_range(state: &mut State) -> Result<(), RuntimeError> {
+    let end = state.pop_int()?;
+    let start = state.pop_int()?;
+
+    let [start_char, end_char] = [start, end].map(|code| u32::try_from(code).ok().and_then(char::from_u32));
+    let (Some(start_char), Some(end_char)) = (start_char, end_char) else {
+        return Err(RuntimeError::InvalidChar(if start_char.is_none() { start } else { end }));
+    };
+    if start > end {
+        return Err(RuntimeError::Other(format!("attempted to build a character range from {start} down to {end}")));
+    }
+
+    let mut count = 0;
+    for c in start_char..=end_char {
+        state.push_string(c.to_string())?;
+        count += 1;
+    }
+
+    state.push_int(count)
+}
+
+// checkpoint
+// checkpoints nest: rollback and commit act on the latest one
+fn i_checkpoint(state: &mut State) -> Result<(), RuntimeError> {
+    let snapshot = state.snapshot();
+    state.checkpoints.push(snapshot);
+    Ok(())
+}
+
+// $code chr
+fn i_chr(state: &mut State) -> Result<(), RuntimeError> {
+    let code = state.pop_int()?;
+
+    let mut out = String::with_capacity(1);
+
+    out.push(match char::from_u32(code as u32) {
+        Some(c) => c,
+        None => return Err(RuntimeError::InvalidChar(code)),
+    });
+
+    state.push_string(out)?;
+    Ok(())
+}
+
+// $value... clear
+// drops every value on the current stack, leaving the other stacks alone
+fn i_clear(state: &mut State) -> Result<(), RuntimeError> {
+    state.stacks[state.current_stack].clear();
+    Ok(())
+}
+
+// commit
+// drops the latest checkpoint, keeping the stacks as they are
+fn i_commit(state: &mut State) -> Result<(), RuntimeError> {
+    if state.checkpoints.pop().is_none() {
+        return Err(RuntimeError::Other("`commit` without a matching `checkpoint`".to_string()))
+    }
+    Ok(())
+}
+
+// $start $end concat
+fn i_concat(state: &mut State) -> Result<(), RuntimeError> {
+    let end = state.pop_string()?;
+    let mut start = state.pop_string()?;
+
+    start.push_str(&end);
+
+    state.push_string(start)?;
+    Ok(())
+}
+
+// $value <=
+fn i_copy_left(state: &mut State) -> Result<(), RuntimeError> {
+    let value = state.pop_value()?;
+    
+    state.switch_to_left_stack()?;
+    state.push_value(value)?;
+    state.switch_to_right_stack()?;
+    Ok(())
+}
+
+// $value =>
+fn i_copy_right(state: &mut State) -> Result<(), RuntimeError> {
+    let value = state.pop_value()?;
+    
+    state.switch_to_right_stack()?;
+    state.push_value(value)?;
+    state.switch_to_left_stack()?;
+    Ok(())
+}
+
+// count_begin
+fn i_count_begin(state: &mut State) -> Result<(), RuntimeError> {
+    state.counters.push(state.steps);
+    Ok(())
+}
+
+// count_end
+// pushes the number of instructions executed since the matching count_begin, excluding both
+fn i_count_end(state: &mut State) -> Result<(), RuntimeError> {
+    let start = match state.counters.pop() {
+        Some(start) => start,
+        None => return Err(RuntimeError::Other("`count_end` without a matching `count_begin`".to_string())),
+    };
+
+    state.push_int((state.steps - start - 1) as INT_TYPE)?;
+    Ok(())
+}
+
+// $dividend $divisor /
+fn i_div(state: &mut State) -> Result<(), RuntimeError> {
+    let divisor = state.pop_int()?;
+    let dividend = state.pop_int()?;
+
+    if divisor == 0 {
+        return Err(RuntimeError::Other(format!("division by zero in `/`: {dividend} / 0")))
+    }
+
+    // only the most negative int divided by -1 overflows
+    match dividend.checked_div(divisor) {
+        Some(result) => state.push_int(result),
+        None => Err(RuntimeError::Other(format!("integer overflow in `/`: {dividend} / {divisor} doesn't fit in an int"))),
+    }
+}
+
+// $value dup
+fn i_dup(state: &mut State) -> Result<(), RuntimeError> {
+    let value = state.pop_value()?;
+
+    state.push_value(value.clone())?;
+    state.push_value(value)?;
+    Ok(())
+}
+
+// $value $stack dup_to
+// a copy goes to the target stack, the original stays where it is
+fn i_dup_to(state: &mut State) -> Result<(), RuntimeError> {
+    let target = state.pop_int()?;
+    let value = state.pop_value()?;
+
+    state.push_value(value.clone())?;
+    state.push_to(target, value)?;
+    Ok(())
+}
+
+// $value dup_under
+// same result as dup, but the copy goes beneath the original
+fn i_dup_under(state: &mut State) -> Result<(), RuntimeError> {
+    let value = state.pop_value()?;
+
+    state.push_value(value.clone())?;
+    state.check_depth(state.current_stack, 1)?;
+    let stack = &mut state.stacks[state.current_stack];
+    stack.insert(stack.len() - 1, value);
+    Ok(())
+}
+
+// empty
+fn i_empty(state: &mut State) -> Result<(), RuntimeError> {
+    state.push_int(state.stacks[state.current_stack].is_empty() as INT_TYPE)?;
+    Ok(())
+}
+
+// input
+// pushes the next line without its line ending; at the end of input it pushes an empty
+// string, and input_eof tells the two apart
+fn i_input(state: &mut State) -> Result<(), RuntimeError> {
+    let line = state.read_line()?;
+    state.input_ended = line.is_none();
+
+    state.push_string(line.unwrap_or_default())?;
+    Ok(())
+}
+
+// input_eof
+// 1 if the latest input found the end of input instead of a line
+fn i_input_eof(state: &mut State) -> Result<(), RuntimeError> {
+    state.push_int(state.input_ended as INT_TYPE)?;
+    Ok(())
+}
+
+// $value $depth insert
+// a depth of 0 inserts on top of the stack
+fn i_insert(state: &mut State) -> Result<(), RuntimeError> {
+    let depth = state.pop_int()?;
+    let value = state.pop_value()?;
+
+    state.check_depth(state.current_stack, 1)?;
+    let stack = &mut state.stacks[state.current_stack];
+    if depth < 0 || depth as usize > stack.len() {
+        return Err(RuntimeError::Other(format!("attempted to insert at depth {depth} in a stack of {} values", stack.len())))
+    }
+
+    let index = stack.len() - depth as usize;
+    stack.insert(index, value);
+    Ok(())
+}
+
+// $string $default int_or_default
+// never fails: anything that isn't an integer in range gives the default
+fn i_int_or_default(state: &mut State) -> Result<(), RuntimeError> {
+    let default = state.pop_int()?;
+    let value = state.pop_value()?;
+
+    state.push_int(value.to_int().unwrap_or(default))?;
+    Ok(())
+}
+
+// $value is_int
+// leaves the value in place and pushes 1 if it is an integer, 0 otherwise
+fn i_is_int(state: &mut State) -> Result<(), RuntimeError> {
+    let is_int = match state.stacks[state.current_stack].last() {
+        Some(value) => value.to_int().is_some(),
+        None => return Err(RuntimeError::StackUnderflow { stack: state.current_stack, needed: 1, available: 0 }),
+    };
+
+    state.push_int(is_int as INT_TYPE)
+}
+
+// $string... $count $delimiter join
+// the inverse of split
+fn i_join(state: &mut State) -> Result<(), RuntimeError> {
+    let delimiter = single_char_delimiter(state.pop_string()?, "join")?;
+    let count = state.pop_int()?;
+
+    let pieces: Vec<_> = state.pop_values(count)?.into_iter().map(Value::into_string).collect();
+    state.push_string(pieces.join(&delimiter.to_string()))
+}
+
+// $path json_get
+// looks the path up in the --json-input document
+fn i_json_get(state: &mut State) -> Result<(), RuntimeError> {
+    let path = state.pop_string()?;
+
+    let Some(json) = &state.json_input else {
+        return Err(RuntimeError::Other("json_get needs --json-input".to_string()))
+    };
+    let value = match json.get(&path) {
+        Some(value) => value.to_value(),
+        None => return Err(RuntimeError::Other(format!("no value at path `{path}` in the JSON input"))),
+    };
+
+    state.push_string(value)?;
+    Ok(())
+}
+
+// debug
+fn i_debug(state: &mut State) -> Result<(), RuntimeError> {
+    state.output.flush();
+    eprintln!("{state}");
+    Ok(())
+}
+
+// $label defer
+// runs the label when the current call returns, even when an error unwinds it
+fn i_defer(state: &mut State) -> Result<(), RuntimeError> {
+    let label = state.pop_string()?;
+
+    state.label_pc(&label)?;
+    match state.call_stack.last_mut() {
+        Some(frame) => frame.deferred.push(label),
+        None => return Err(RuntimeError::Other(format!("cannot defer `{label}` outside of a call"))),
+    }
+    Ok(())
+}
+
+// depth
+// pushes how many values are on the current stack
+fn i_depth(state: &mut State) -> Result<(), RuntimeError> {
+    state.push_int(state.stacks[state.current_stack].len() as INT_TYPE)
+}
+
+// $message die
+// stops the program with a failing exit code
+fn i_die(state: &mut State) -> Result<(), RuntimeError> {
+    let message = state.pop_string()?;
+
+    state.output.flush();
+    eprintln!("{message}");
+    state.halted = Some(1);
+    Ok(())
+}
+
+// $value... $count discard
+// drops the top count values, so `1 discard` is `pop`
+fn i_discard(state: &mut State) -> Result<(), RuntimeError> {
+    let count = state.pop_int()?;
+    let stack = &mut state.stacks[state.current_stack];
+
+    if count < 0 {
+        return Err(RuntimeError::Other(format!("attempted to discard a negative number of values: {count}")));
+    }
+    if count as usize > stack.len() {
+        return Err(RuntimeError::StackUnderflow { stack: state.current_stack, needed: count as usize, available: stack.len() });
+    }
+
+    stack.truncate(stack.len() - count as usize);
+    Ok(())
+}
+
+// $string display_width
+// how many terminal columns the string takes, see char_width
+fn i_display_width(state: &mut State) -> Result<(), RuntimeError> {
+    let string = state.pop_string()?;
+
+    let width: usize = string.chars().map(char_width).sum();
+    state.push_int(width as INT_TYPE)?;
+    Ok(())
+}
+
+// $label for_each_line
+// calls the label with each line of input on top of the stack, until the end of input
+fn i_for_each_line(state: &mut State) -> Result<(), RuntimeError> {
+    let label = state.pop_string()?;
+
+    let pc = state.label_pc(&label)?;
+    if let Some(line) = state.read_line()? {
+        state.push_frame(Frame { return_pc: state.pc, label, kind: FrameKind::ForEachLine, deferred: Vec::new() })?;
+        state.push_string(line)?;
+        state.pc = pc;
+    }
+    Ok(())
+}
+
+// $byte... $count from_bytes
+// the inverse of to_bytes; the bytes have to be valid UTF-8
+fn i_from_bytes(state: &mut State) -> Result<(), RuntimeError> {
+    let count = state.pop_int()?;
+
+    let mut bytes = Vec::new();
+    for value in state.pop_values(count)? {
+        let Some(byte) = value.to_int().and_then(|int| u8::try_from(int).ok()) else {
+            return Err(RuntimeError::Other(format!("attempted to use {value:?} as a byte in `from_bytes`")));
+        };
+        bytes.push(byte);
+    }
+
+    match String::from_utf8(bytes) {
+        Ok(string) => state.push_string(string),
+        Err(e) => Err(RuntimeError::Other(format!(
+            "attempted to build a string from bytes that are not valid UTF-8 at byte {}", e.utf8_error().valid_up_to()
+        ))),
+    }
+}
+
+// $base $by ge
+fn i_ge(state: &mut State) -> Result<(), RuntimeError> {
+    let by = state.pop_int()?;
+    let base = state.pop_int()?;
+
+    state.push_int((base >= by) as INT_TYPE)?;
+    Ok(())
+}
+
+// $x $y ==
+fn i_eq(state: &mut State) -> Result<(), RuntimeError> {
+    let y = state.pop_value()?;
+    let x = state.pop_value()?;
+
+    state.push_int((x == y) as INT_TYPE)?;
+    Ok(())
+}
+
+// $string explode
+// pushes each character as its own string, first character deepest, then how many there are
+fn i_explode(state: &mut State) -> Result<(), RuntimeError> {
+    let string = state.pop_string()?;
+
+    let mut count = 0;
+    for c in string.chars() {
+        state.push_string(c.to_string())?;
+        count += 1;
+    }
+    state.push_int(count)
+}
+
+// $name fill
+// moves every value of the named stack onto the current one, keeping their order
+fn i_fill(state: &mut State) -> Result<(), RuntimeError> {
+    let name = state.pop_string()?;
+
+    state.check_depth(state.current_stack, state.named_stacks.get(&name).map_or(0, Vec::len))?;
+    let values = state.named_stacks.remove(&name).unwrap_or_default();
+    state.stacks[state.current_stack].extend(values);
+    Ok(())
+}
+
+// flatten
+// gathers every stack into a single stack 0, which becomes the current one: the values of
+// stack 0 stay at the bottom, stack 1's go on top of them, and so on, each in its own order
+fn i_flatten(state: &mut State) -> Result<(), RuntimeError> {
+    let total: usize = state.stacks.iter().map(Vec::len).sum();
+    state.check_depth(0, total - state.stacks[0].len())?;
+
+    let mut flat = Vec::with_capacity(total);
+    for stack in std::mem::take(&mut state.stacks) {
+        flat.extend(stack);
+    }
+    state.stacks = vec![flat];
+    state.current_stack = 0;
+    Ok(())
+}
+
+// $string $index .
+fn i_get(state: &mut State) -> Result<(), RuntimeError> {
+    let index = state.pop_int()?;
+    let string = state.pop_string()?;
+
+    let mut c = String::with_capacity(1);
+    c.push(match string.chars().nth(index as usize) {
+        Some(val) => val,
+        None => return Err(RuntimeError::Other(format!("attempted to access string {string:?} with an invalid index: {index}"))),
+    });
+
+    state.push_string(c)?;
+    Ok(())
+}
+ 
+// $condition $label goto_if
+fn i_goto_if(state: &mut State) -> Result<(), RuntimeError> {
+    let label = state.pop_string()?;
+    let condition = state.pop_int()?;
+
+    if condition != 0 {
+        state.pc = state.label_pc(&label)?;
+    }
+    Ok(())
+}
+
+// $index $prefix goto_indexed
+// jumps to the label named by the prefix followed by the index, like `case3`
+fn i_goto_indexed(state: &mut State) -> Result<(), RuntimeError> {
+    let prefix = state.pop_string()?;
+    let index = state.pop_int()?;
+
+    let label = format!("{prefix}{index}");
+    state.pc = match state.labels.get(&label) {
+        Some(pc) => *pc,
+        None => return Err(RuntimeError::Other(format!("unknown label `{label}` in `goto_indexed`"))),
+    };
+    Ok(())
+}
+
+// $index goto_stack
+// switches to any stack at once, creating the stacks up to it like moving right does
+fn i_goto_stack(state: &mut State) -> Result<(), RuntimeError> {
+    let index = state.pop_int()?;
+
+    state.stack_mut(index)?;
+    state.current_stack = index as usize;
+    Ok(())
+}
+
+// $base $by gt
+fn i_gt(state: &mut State) -> Result<(), RuntimeError> {
+    let by = state.pop_int()?;
+    let base = state.pop_int()?;
+
+    state.push_int((base > by) as INT_TYPE)?;
+    Ok(())
+}
+
+// $code halt
+// stops the program, exiting with the code
+fn i_halt(state: &mut State) -> Result<(), RuntimeError> {
+    let code = state.pop_int()?;
+
+    match i32::try_from(code) {
+        Ok(code) => state.halted = Some(code),
+        Err(_) => return Err(RuntimeError::Other(format!("attempted to halt with the out of range exit code {code}"))),
+    }
+    Ok(())
+}
+
+// home
+// switches back to stack 0 from anywhere
+fn i_home(state: &mut State) -> Result<(), RuntimeError> {
+    state.current_stack = 0;
+    Ok(())
+}
+
+// $value... $count implode
+// the inverse of explode, joining the values with the deepest first
+fn i_implode(state: &mut State) -> Result<(), RuntimeError> {
+    let count = state.pop_int()?;
+
+    let mut string = String::new();
+    for value in state.pop_values(count)? {
+        string.push_str(&value.into_string());
+    }
+    state.push_string(string)
+}
+
+// $pc jump
+fn i_jump(state: &mut State) -> Result<(), RuntimeError> {
+    let pc = state.pop_int()?;
+
+    state.pc = state.jump_target(pc)?;
+    Ok(())
+}
+
+// $condition $pc jump_if
+fn i_jump_if(state: &mut State) -> Result<(), RuntimeError> {
+    let pc = state.pop_int()?;
+    let condition = state.pop_int()?;
+
+    if condition != 0 {
+        state.pc = state.jump_target(pc)?;
+    }
+    Ok(())
+}
+
+// $base $by le
+fn i_le(state: &mut State) -> Result<(), RuntimeError> {
+    let by = state.pop_int()?;
+    let base = state.pop_int()?;
+
+    state.push_int((base <= by) as INT_TYPE)?;
+    Ok(())
+}
+
+// $string len
+fn i_len(state: &mut State) -> Result<(), RuntimeError> {
+    let string = state.pop_string()?;
+
+    state.push_int(string.chars().count() as INT_TYPE)?;
+    Ok(())
+}
+
+// $base $by lt
+fn i_lt(state: &mut State) -> Result<(), RuntimeError> {
+    let by = state.pop_int()?;
+    let base = state.pop_int()?;
+
+    state.push_int((base < by) as INT_TYPE)?;
+    Ok(())
+}
+
+// $a $b max
+fn i_max(state: &mut State) -> Result<(), RuntimeError> {
+    let b = state.pop_int()?;
+    let a = state.pop_int()?;
+
+    state.push_int(a.max(b))?;
+    Ok(())
+}
+
+// $value... $count max_n
+fn i_max_n(state: &mut State) -> Result<(), RuntimeError> {
+    let count = state.pop_int()?;
+
+    match state.pop_ints(count)?.into_iter().max() {
+        Some(max) => state.push_int(max)?,
+        None => return Err(RuntimeError::Other("attempted to take the maximum of 0 values".to_string())),
+    }
+    Ok(())
+}
+
+// $argument $label memo_call
+// like call, for labels that replace the value on top with a result that only depends
+// on it; later calls with the same argument push the remembered result instead
+fn i_memo_call(state: &mut State) -> Result<(), RuntimeError> {
+    let label = state.pop_string()?;
+    let argument = state.pop_value()?;
+
+    let key = argument.to_string();
+    if let Some(result) = state.memo.get(&(label.clone(), key.clone())) {
+        return state.push_value(result.clone());
+    }
+
+    let pc = state.label_pc(&label)?;
+    state.push_value(argument)?;
+    state.push_frame(Frame { return_pc: state.pc, label, kind: FrameKind::Memo(key), deferred: Vec::new() })?;
+    state.pc = pc;
+    Ok(())
+}
+
+// $a $b min
+fn i_min(state: &mut State) -> Result<(), RuntimeError> {
+    let b = state.pop_int()?;
+    let a = state.pop_int()?;
+
+    state.push_int(a.min(b))?;
+    Ok(())
+}
+
+// $value... $count min_n
+fn i_min_n(state: &mut State) -> Result<(), RuntimeError> {
+    let count = state.pop_int()?;
+
+    match state.pop_ints(count)?.into_iter().min() {
+        Some(min) => state.push_int(min)?,
+        None => return Err(RuntimeError::Other("attempted to take the minimum of 0 values".to_string())),
+    }
+    Ok(())
+}
+
+// $x $y *
+fn i_mul(state: &mut State) -> Result<(), RuntimeError> {
+    let y = state.pop_int()?;
+    let x = state.pop_int()?;
+
+    match x.checked_mul(y) {
+        Some(result) => state.push_int(result),
+        None => Err(RuntimeError::Other(format!("integer overflow in `*`: {x} * {y} doesn't fit in an int"))),
+    }
+}
+
+// $value $modulo %
+fn i_mod(state: &mut State) -> Result<(), RuntimeError> {
+    let modulo = state.pop_int()?;
+    let value = state.pop_int()?;
+
+    if modulo == 0 {
+        return Err(RuntimeError::Other(format!("division by zero in `%`: {value} % 0")))
+    }
+
+    match value.checked_rem(modulo) {
+        Some(result) => state.push_int(result),
+        None => Err(RuntimeError::Other(format!("integer overflow in `%`: {value} % {modulo} doesn't fit in an int"))),
+    }
+}
+
+// $value $stack $condition move_if
+// leaves the value where it is if the condition is false
+fn i_move_if(state: &mut State) -> Result<(), RuntimeError> {
+    let condition = state.pop_int()?;
+    let target = state.pop_int()?;
+
+    if condition != 0 {
+        let value = state.pop_value()?;
+        state.push_to(target, value)?;
+    }
+    Ok(())
+}
+
+// $x $y !=
+fn i_neq(state: &mut State) -> Result<(), RuntimeError> {
+    let y = state.pop_value()?;
+    let x = state.pop_value()?;
+
+    state.push_int((x != y) as INT_TYPE)?;
+    Ok(())
+}
+
+// $string normalize_nl
+// turns `\r\n` and lone `\r` line endings into `\n`
+fn i_normalize_nl(state: &mut State) -> Result<(), RuntimeError> {
+    let string = state.pop_string()?;
+
+    state.push_string(string.replace("\r\n", "\n").replace('\r', "\n"))?;
+    Ok(())
+}
+
+// $condition not
+// like goto_if, any int other than 0 is true
+fn i_not(state: &mut State) -> Result<(), RuntimeError> {
+    let condition = state.pop_int()?;
+
+    state.push_int((condition == 0) as INT_TYPE)?;
+    Ok(())
+}
+
+// $a $b or
+fn i_or(state: &mut State) -> Result<(), RuntimeError> {
+    let b = state.pop_int()?;
+    let a = state.pop_int()?;
+
+    state.push_int((a != 0 || b != 0) as INT_TYPE)?;
+    Ok(())
+}
+
+// $string ord
+// the code point of the first character, the inverse of chr
+fn i_ord(state: &mut State) -> Result<(), RuntimeError> {
+    let string = state.pop_string()?;
+
+    match string.chars().next() {
+        Some(c) => state.push_int(c as INT_TYPE)?,
+        None => return Err(RuntimeError::Other("attempted to take `ord` of an empty string".to_string())),
+    }
+    Ok(())
+}
+
+// $a $b over
+// copies `a` to the top: a b -> a b a
+fn i_over(state: &mut State) -> Result<(), RuntimeError> {
+    let values = state.pop_values(2)?;
+    let copy = values[0].clone();
+
+    for value in values {
+        state.push_value(value)?;
+    }
+    state.push_value(copy)?;
+    Ok(())
+}
+
+// $value... $count pack
+// each value is encoded as `<byte length>:<value>`, so values may contain any character
+fn i_pack(state: &mut State) -> Result<(), RuntimeError> {
+    let count = state.pop_int()?;
+
+    let mut packed = String::new();
+    for value in state.pop_values(count)? {
+        packed.push_str(&format!("{}:{value}", value.len()));
+    }
+
+    state.push_string(packed)?;
+    Ok(())
+}
+
+// $string parse_num
+fn i_parse_num(state: &mut State) -> Result<(), RuntimeError> {
+    let string = state.pop_string()?;
+
+    let (negative, digits) = match string.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, string.as_str()),
+    };
+    let (radix, digits) = match digits.get(..2) {
+        Some("0x" | "0X") => (16, &digits[2..]),
+        Some("0b" | "0B") => (2, &digits[2..]),
+        Some("0o" | "0O") => (8, &digits[2..]),
+        _ => (10, digits),
+    };
+
+    // from_str_radix accepts its own sign, which would allow "--5" or "0x-5"
+    if digits.starts_with(['+', '-']) {
+        return Err(RuntimeError::Other(format!("failed to parse {string:?} as a number")));
+    }
+
+    // parsed wide so that the most negative value doesn't overflow before negation
+    let value = i128::from_str_radix(digits, radix)
+        .ok()
+        .map(|value| if negative { -value } else { value })
+        .and_then(|value| INT_TYPE::try_from(value).ok());
+
+    let value = match value {
+        Some(value) => value,
+        None => return Err(RuntimeError::Other(format!("failed to parse {string:?} as a number"))),
+    };
+
+    state.push_int(value)?;
+    Ok(())
+}
+
+// $value peek
+// prints the value like `print`, but leaves it on the stack
+fn i_peek(state: &mut State) -> Result<(), RuntimeError> {
+    let value = match state.stacks[state.current_stack].last() {
+        Some(value) => value.to_string(),
+        None => return Err(RuntimeError::StackUnderflow { stack: state.current_stack, needed: 1, available: 0 }),
+    };
+
+    state.output.write(&value);
+    Ok(())
+}
+
+// $stack peek_stack
+fn i_peek_stack(state: &mut State) -> Result<(), RuntimeError> {
+    let index = state.pop_int()?;
+
+    let value = match state.stacks.get(index as usize) {
+        _ if index < 0 => return Err(RuntimeError::Other(format!("attempted to peek at a negative stack index: {index}"))),
+        Some(stack) => match stack.last() {
+            Some(value) => value.clone(),
+            None => return Err(RuntimeError::Other(format!("attempted to peek at empty stack {index}"))),
+        },
+        None => return Err(RuntimeError::Other(format!("attempted to peek at stack {index}, but there are only {} stacks", state.stacks.len()))),
+    };
+
+    state.push_value(value)?;
+    Ok(())
+}
+
+// $value pop
+fn i_pop(state: &mut State) -> Result<(), RuntimeError> {
+    state.pop_value()?;
+    Ok(())
+}
+
+// pop_stack_ptr
+// goes back to the stack that was current at the latest push_stack_ptr
+fn i_pop_stack_ptr(state: &mut State) -> Result<(), RuntimeError> {
+    match state.stack_ptrs.pop() {
+        Some(stack) => state.current_stack = stack,
+        None => return Err(RuntimeError::Other("`pop_stack_ptr` without a matching `push_stack_ptr`".to_string())),
+    }
+    Ok(())
+}
+
+// $base $exponent pow
+fn i_pow(state: &mut State) -> Result<(), RuntimeError> {
+    let exponent = state.pop_int()?;
+    let base = state.pop_int()?;
+
+    if exponent < 0 {
+        return Err(RuntimeError::Other(format!("attempted to raise {base} to the negative power {exponent}")));
+    }
+    // exponents past u32 only fit for bases of 0, 1 and -1
+    let power = match u32::try_from(exponent) {
+        Ok(exponent) => base.checked_pow(exponent),
+        Err(_) => match base {
+            0 | 1 => Some(base),
+            -1 => Some(if exponent % 2 == 0 { 1 } else { -1 }),
+            _ => None,
+        },
+    };
+    match power {
+        Some(power) => state.push_int(power)?,
+        None => return Err(RuntimeError::Other(format!("integer overflow in `pow`: {base} ^ {exponent} doesn't fit in an int"))),
+    }
+    Ok(())
+}
+
+// $value print
+fn i_print(state: &mut State) -> Result<(), RuntimeError> {
+    let value = state.pop_string()?;
+
+    state.output.write(&value);
+    Ok(())
+}
+
+// $x $y scmp
+// compares by Unicode scalar value, not by locale
+fn i_scmp(state: &mut State) -> Result<(), RuntimeError> {
+    let y = state.pop_string()?;
+    let x = state.pop_string()?;
+
+    state.push_int(match x.cmp(&y) {
+        std::cmp::Ordering::Less => -1,
+        std::cmp::Ordering::Equal => 0,
+        std::cmp::Ordering::Greater => 1,
+    })?;
+    Ok(())
+}
+
+// $value... $count product_n
+// the product of 0 values is 1
+fn i_product_n(state: &mut State) -> Result<(), RuntimeError> {
+    let count = state.pop_int()?;
+
+    let product = state.pop_ints(count)?
+        .into_iter()
+        .try_fold(1 as INT_TYPE, |acc, value| acc.checked_mul(value));
+
+    match product {
+        Some(product) => state.push_int(product)?,
+        None => return Err(RuntimeError::Other("integer overflow in `product_n`".to_string())),
+    }
+    Ok(())
+}
+
+// push_stack_ptr
+// remembers which stack is the current one, for pop_stack_ptr
+fn i_push_stack_ptr(state: &mut State) -> Result<(), RuntimeError> {
+    state.stack_ptrs.push(state.current_stack);
+    Ok(())
+}
+
+// $lo $hi rand
+// a pseudo-random integer from lo up to but excluding hi, reproducible after `seed`
+fn i_rand(state: &mut State) -> Result<(), RuntimeError> {
+    let hi = state.pop_int()?;
+    let lo = state.pop_int()?;
+    if lo >= hi {
+        return Err(RuntimeError::Other(format!("attempted to pick a random integer from the empty range {lo} to {hi}")));
+    }
+
+    // the width of the range can go past INT_TYPE::MAX, but always fits in a u64
+    let width = hi.abs_diff(lo);
+    let offset = state.rng.next() % width;
+    state.push_int(lo.wrapping_add(offset as INT_TYPE))
+}
+
+// $length rand_str
+fn i_rand_str(state: &mut State) -> Result<(), RuntimeError> {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789";
+
+    let length = state.pop_int()?;
+    if length < 0 {
+        return Err(RuntimeError::Other(format!("attempted to generate a random string with a negative length: {length}")))
+    }
+    state.check_string_len(length as usize)?;
+
+    let out = (0..length)
+        .map(|_| ALPHABET[(state.rng.next() % ALPHABET.len() as u64) as usize] as char)
+        .collect();
+
+    state.push_string(out)?;
+    Ok(())
+}
+
+// $start $end range
+// pushes start, start + 1, ... up to but excluding end, then how many there are;
+// an end at or below the start gives an empty range
+fn i_range(state: &mut State) -> Result<(), RuntimeError> {
+    let end = state.pop_int()?;
+    let start = state.pop_int()?;
+
+    let len = end.saturating_sub(start).max(0) as u64;
+    if len > MAX_RANGE_LEN {
+        return Err(RuntimeError::Other(format!("range of {len} values exceeds the maximum of {MAX_RANGE_LEN}")));
+    }
+    // fail before pushing anything rather than halfway
+    state.check_depth(state.current_stack, len as usize + 1)?;
+
+    for i in start..end {
+        state.push_int(i)?;
+    }
+
+    state.push_int(len as INT_TYPE)
+}
+
+// read_all
+fn i_read_all(state: &mut State) -> Result<(), RuntimeError> {
+    state.output.flush();
+    let value = decode_input(state.input.read_to_end()?, state.options.lossy_utf8_input)?;
+
+    state.push_string(value)?;
+    Ok(())
+}
+
+// read_byte
+// pushes -1 at the end of input
+fn i_read_byte(state: &mut State) -> Result<(), RuntimeError> {
+    state.output.flush();
+    let value = match state.input.read_byte()? {
+        Some(byte) => byte as INT_TYPE,
+        None => -1,
+    };
+
+    state.push_int(value)?;
+    Ok(())
+}
+
+// $path read_file
+fn i_read_file(state: &mut State) -> Result<(), RuntimeError> {
+    state.check_files_allowed("read_file")?;
+    let path = state.pop_string()?;
+
+    match fs::read_to_string(&path) {
+        Ok(contents) => state.push_string(contents),
+        Err(e) => Err(RuntimeError::Other(format!("failed to read `{path}`: {e}"))),
+    }
+}
+
+// $depth remove_at
+// a depth of 0 removes the top of the stack
+fn i_remove_at(state: &mut State) -> Result<(), RuntimeError> {
+    let depth = state.pop_int()?;
+
+    let stack = &mut state.stacks[state.current_stack];
+    if depth < 0 || depth as usize >= stack.len() {
+        return Err(RuntimeError::Other(format!("attempted to remove at depth {depth} in a stack of {} values", stack.len())))
+    }
+
+    let index = stack.len() - 1 - depth as usize;
+    let value = stack.remove(index);
+
+    state.push_value(value)?;
+    Ok(())
+}
+
+// $code $count repeat_char
+fn i_repeat_char(state: &mut State) -> Result<(), RuntimeError> {
+    let count = state.pop_int()?;
+    let code = state.pop_int()?;
+
+    if count < 0 {
+        return Err(RuntimeError::Other(format!("attempted to repeat a character a negative number of times: {count}")))
+    }
+    let c = match u32::try_from(code).ok().and_then(char::from_u32) {
+        Some(c) => c,
+        None => return Err(RuntimeError::InvalidChar(code)),
+    };
+    state.check_string_len(count as usize)?;
+
+    state.push_string(std::iter::repeat_n(c, count as usize).collect())?;
+    Ok(())
+}
+
+// $target $pattern $replacement replace_first
+// an empty pattern matches at the start, so the replacement is prepended
+fn i_replace_first(state: &mut State) -> Result<(), RuntimeError> {
+    let replacement = state.pop_string()?;
+    let pattern = state.pop_string()?;
+    let target = state.pop_string()?;
+
+    state.push_string(target.replacen(&pattern, &replacement, 1))?;
+    Ok(())
+}
+
+// ret
+fn i_ret(state: &mut State) -> Result<(), RuntimeError> {
+    let mut frame = match state.call_stack.pop() {
+        Some(frame) => frame,
+        None => return Err(RuntimeError::Other("attempted to return with an empty call stack".to_string())),
+    };
+
+    if let Some(label) = frame.deferred.pop() {
+        return state.run_deferred(frame, label);
+    }
+    if frame.kind == FrameKind::Deferred {
+        return i_ret(state);
+    }
+    if state.unwinding.is_some() {
+        return state.unwind();
+    }
+
+    if let FrameKind::Memo(argument) = &frame.kind {
+        let result = match state.stacks[state.current_stack].last() {
+            Some(result) => result.clone(),
+            None => return Err(RuntimeError::Other(format!("`{}` returned to memo_call without a result", frame.label))),
+        };
+        state.memo.insert((frame.label.clone(), argument.clone()), result);
+    }
+
+    if frame.kind == FrameKind::ForEachLine {
+        if let Some(line) = state.read_line()? {
+            state.pc = state.label_pc(&frame.label)?;
+            state.call_stack.push(frame);
+            state.push_string(line)?;
+            return Ok(());
+        }
+    }
+
+    state.pc = frame.return_pc;
+    Ok(())
+}
+
+// $value... reverse
+// reverses the current stack in place, so the deepest value ends up on top
+fn i_reverse(state: &mut State) -> Result<(), RuntimeError> {
+    state.stacks[state.current_stack].reverse();
+    Ok(())
+}
+
+// rollback
+// puts every stack back the way it was at the latest checkpoint, and drops that checkpoint
+fn i_rollback(state: &mut State) -> Result<(), RuntimeError> {
+    match state.checkpoints.pop() {
+        Some(snapshot) => state.restore(snapshot),
+        None => return Err(RuntimeError::Other("`rollback` without a matching `checkpoint`".to_string())),
+    }
+    Ok(())
+}
+
+// $a $b $c rot
+// brings `a` to the top: a b c -> b c a
+fn i_rot(state: &mut State) -> Result<(), RuntimeError> {
+    let mut values = state.pop_values(3)?;
+    values.rotate_left(1);
+
+    for value in values {
+        state.push_value(value)?;
+    }
+    Ok(())
+}
+
+// $string $shift rotate_str
+// rotates left by the shift in characters, or right if it is negative
+fn i_rotate_str(state: &mut State) -> Result<(), RuntimeError> {
+    let shift = state.pop_int()?;
+    let string = state.pop_string()?;
+
+    let chars: Vec<char> = string.chars().collect();
+    if chars.is_empty() {
+        state.push_string(string)?;
+        return Ok(());
+    }
+
+    let shift = shift.rem_euclid(chars.len() as INT_TYPE) as usize;
+    let rotated = chars[shift..].iter().chain(&chars[..shift]).collect();
+
+    state.push_string(rotated)?;
+    Ok(())
+}
+
+// !
+fn i_save_pc(state: &mut State) -> Result<(), RuntimeError> {
+    state.push_int(state.pc as INT_TYPE)?;
+    Ok(())
+}
+
+// $seed seed
+fn i_seed(state: &mut State) -> Result<(), RuntimeError> {
+    let seed = state.pop_int()?;
+
+    state.rng = Rng::new(seed as u64);
+    Ok(())
+}
+
+// $depth set_depth
+// drops values until the stack is that deep, a shallower stack is an error rather than padded
+fn i_set_depth(state: &mut State) -> Result<(), RuntimeError> {
+    let depth = state.pop_int()?;
+
+    let stack = &mut state.stacks[state.current_stack];
+    if depth < 0 || depth as usize > stack.len() {
+        return Err(RuntimeError::Other(format!("attempted to set the depth to {depth} in a stack of {} values", stack.len())))
+    }
+
+    stack.truncate(depth as usize);
+    Ok(())
+}
+
+// $value $amount shl
+// bits shifted past the top are dropped
+fn i_shl(state: &mut State) -> Result<(), RuntimeError> {
+    let amount = pop_shift_amount(state, "shl")?;
+    let value = state.pop_int()?;
+
+    state.push_int(value << amount)
+}
+
+// $value $amount shr
+// keeps the sign, so negative values stay negative
+fn i_shr(state: &mut State) -> Result<(), RuntimeError> {
+    let amount = pop_shift_amount(state, "shr")?;
+    let value = state.pop_int()?;
+
+    state.push_int(value >> amount)
+}
+
+// $name spill
+// moves every value of the current stack onto the named one, keeping their order
+fn i_spill(state: &mut State) -> Result<(), RuntimeError> {
+    let name = state.pop_string()?;
+
+    let values = std::mem::take(&mut state.stacks[state.current_stack]);
+    state.named_stacks.entry(name).or_default().extend(values);
+    Ok(())
+}
+
+// $string $delimiter split
+// pushes each piece, the first one deepest, then how many there are
+fn i_split(state: &mut State) -> Result<(), RuntimeError> {
+    let delimiter = single_char_delimiter(state.pop_string()?, "split")?;
+    let string = state.pop_string()?;
+
+    let mut count = 0;
+    for piece in string.split(delimiter) {
+        state.push_string(piece.to_string())?;
+        count += 1;
+    }
+    state.push_int(count)
+}
+
+// $message todo
+fn i_todo(state: &mut State) -> Result<(), RuntimeError> {
+    let message = state.pop_string()?;
+
+    Err(RuntimeError::Other(format!("not yet implemented: {message}")))
+}
+
+// unreachable
+fn i_unreachable(state: &mut State) -> Result<(), RuntimeError> {
+    Err(RuntimeError::Other(format!("reached unreachable code at pc {}", state.pc)))
+}
+
+// $string uri_decode
+fn i_uri_decode(state: &mut State) -> Result<(), RuntimeError> {
+    let string = state.pop_string()?;
+
+    let mut bytes = Vec::with_capacity(string.len());
+    let mut rest = string.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'%' {
+            let decoded = tail.get(..2)
+                .and_then(|hex| std::str::from_utf8(hex).ok())
+                .filter(|hex| hex.bytes().all(|b| b.is_ascii_hexdigit()))
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+
+            match decoded {
+                Some(decoded) => bytes.push(decoded),
+                None => return Err(RuntimeError::Other(format!("malformed percent-encoding in {string:?}"))),
+            }
+            rest = &tail[2..];
+        } else {
+            bytes.push(byte);
+            rest = tail;
+        }
+    }
+
+    match String::from_utf8(bytes) {
+        Ok(decoded) => state.push_string(decoded)?,
+        Err(_) => return Err(RuntimeError::Other(format!("percent-encoded {string:?} is not valid UTF-8"))),
+    }
+    Ok(())
+}
+
+// $string uri_encode
+// everything but the RFC 3986 unreserved characters is percent-encoded
+fn i_uri_encode(state: &mut State) -> Result<(), RuntimeError> {
+    let string = state.pop_string()?;
+
+    let mut encoded = String::with_capacity(string.len());
+    for byte in string.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{byte:02X}")),
+        }
+    }
+
+    state.push_string(encoded)?;
+    Ok(())
+}
+
+// $contents $path write_file
+// replaces the file if it exists
+fn i_write_file(state: &mut State) -> Result<(), RuntimeError> {
+    state.check_files_allowed("write_file")?;
+    let path = state.pop_string()?;
+    let contents = state.pop_string()?;
+
+    match fs::write(&path, contents) {
+        Ok(()) => Ok(()),
+        Err(e) => Err(RuntimeError::Other(format!("failed to write `{path}`: {e}"))),
+    }
+}
+
+// $string swap_case
+// uses the full Unicode case mappings, so a character may become several
+// (`ß` becomes `SS`) and swapping twice doesn't always give back the original
+fn i_swap_case(state: &mut State) -> Result<(), RuntimeError> {
+    let string = state.pop_string()?;
+
+    let mut out = String::with_capacity(string.len());
+    for c in string.chars() {
+        if c.is_lowercase() {
+            out.extend(c.to_uppercase());
+        } else if c.is_uppercase() {
+            out.extend(c.to_lowercase());
+        } else {
+            out.push(c);
+        }
+    }
+
+    state.push_string(out)?;
+    Ok(())
+}
+
+// $value $tag tag
+// annotates the value for `debug` and traces, replacing any earlier tag
+fn i_tag(state: &mut State) -> Result<(), RuntimeError> {
+    let tag = state.pop_string()?;
+    let value = state.pop_value()?.untagged();
+
+    state.push_value(Value::Tagged(Box::new(value), tag))
+}
+
+// $string to_bytes
+// pushes each UTF-8 byte of the string, then how many there are
+fn i_to_bytes(state: &mut State) -> Result<(), RuntimeError> {
+    let string = state.pop_string()?;
+
+    for byte in string.bytes() {
+        state.push_int(byte as INT_TYPE)?;
+    }
+    state.push_int(string.len() as INT_TYPE)
+}
+
+// $value to_int
+// fails unless the value is an integer, like arithmetic would
+fn i_to_int(state: &mut State) -> Result<(), RuntimeError> {
+    let value = state.pop_int()?;
+
+    state.push_int(value)
+}
+
+// $value to_str
+fn i_to_str(state: &mut State) -> Result<(), RuntimeError> {
+    let value = state.pop_string()?;
+
+    state.push_string(value)
+}
+
+// $packed unpack
+fn i_unpack(state: &mut State) -> Result<(), RuntimeError> {
+    let packed = state.pop_string()?;
+
+    let mut rest = packed.as_str();
+    let mut count = 0;
+    while !rest.is_empty() {
+        let value = rest.split_once(':')
+            .and_then(|(len, tail)| Some((len.parse::<usize>().ok()?, tail)))
+            .and_then(|(len, tail)| Some((tail.get(..len)?, tail.get(len..)?)));
+
+        let (value, tail) = match value {
+            Some(value) => value,
+            None => return Err(RuntimeError::Other(format!("attempted to unpack a malformed packed value: {packed:?}"))),
+        };
+
+        state.push_string(value.to_string())?;
+        count += 1;
+        rest = tail;
+    }
+
+    state.push_int(count)?;
+    Ok(())
+}
+
+// $value... $count sum_n
+// the sum of 0 values is 0
+fn i_sum_n(state: &mut State) -> Result<(), RuntimeError> {
+    let count = state.pop_int()?;
+
+    let sum = state.pop_ints(count)?
+        .into_iter()
+        .try_fold(0 as INT_TYPE, |acc, value| acc.checked_add(value));
+
+    match sum {
+        Some(sum) => state.push_int(sum)?,
+        None => return Err(RuntimeError::Other("integer overflow in `sum_n`".to_string())),
+    }
+    Ok(())
+}
+
+// $a $b swap
+fn i_swap(state: &mut State) -> Result<(), RuntimeError> {
+    let mut values = state.pop_values(2)?;
+    values.swap(0, 1);
+
+    for value in values {
+        state.push_value(value)?;
+    }
+    Ok(())
+}
+
+// src_file
+fn i_src_file(state: &mut State) -> Result<(), RuntimeError> {
+    let file = match state.source_map.locate(state.pc) {
+        Some((file, _, _)) => file.to_string(),
+        None => "<unknown>".to_string(),
+    };
+
+    state.push_string(file)?;
+    Ok(())
+}
+
+// src_line
+fn i_src_line(state: &mut State) -> Result<(), RuntimeError> {
+    let line = match state.source_map.locate(state.pc) {
+        Some((_, line, _)) => line,
+        None => 0,
+    };
+
+    state.push_int(line as INT_TYPE)?;
+    Ok(())
+}
+
+// $value stack_contains
+// compares values like `==` and leaves the stack as it is
+fn i_stack_contains(state: &mut State) -> Result<(), RuntimeError> {
+    let value = state.pop_value()?;
+
+    let found = state.stacks[state.current_stack].contains(&value);
+    state.push_int(found as INT_TYPE)?;
+    Ok(())
+}
+
+// stack_index
+// pushes the index of the current stack, 0 being the leftmost
+fn i_stack_index(state: &mut State) -> Result<(), RuntimeError> {
+    state.push_int(state.current_stack as INT_TYPE)
+}
+
+// $stack1 $stack2 stacks_equal
+// compares values the same way as ==, leaving both stacks untouched
+fn i_stacks_equal(state: &mut State) -> Result<(), RuntimeError> {
+    let second = state.pop_int()?;
+    let first = state.pop_int()?;
+
+    let stack = |index: INT_TYPE| match usize::try_from(index).ok().and_then(|i| state.stacks.get(i)) {
+        Some(stack) => Ok(stack),
+        None => Err(RuntimeError::Other(format!("attempted to compare stack {index}, but there are only {} stacks", state.stacks.len()))),
+    };
+
+    let equal = stack(first)? == stack(second)?;
+    state.push_int(equal as INT_TYPE)?;
+    Ok(())
+}
+
+// $base $by -
+fn i_sub(state: &mut State) -> Result<(), RuntimeError> {
+    let by = state.pop_int()?;
+    let base = state.pop_int()?;
+
+    match base.checked_sub(by) {
+        Some(result) => state.push_int(result),
+        None => Err(RuntimeError::Other(format!("integer overflow in `-`: {base} - {by} doesn't fit in an int"))),
+    }
+}
+
+// $string $start $end substr
+// the characters from start up to, but not including, end
+fn i_substr(state: &mut State) -> Result<(), RuntimeError> {
+    let end = state.pop_int()?;
+    let start = state.pop_int()?;
+    let string = state.pop_string()?;
+
+    let len = string.chars().count() as INT_TYPE;
+    if start < 0 || start > end || end > len {
+        return Err(RuntimeError::Other(format!(
+            "`substr` bounds {start}..{end} are out of range for a string of {len} characters"
+        )));
+    }
+
+    let substring = string.chars().skip(start as usize).take((end - start) as usize).collect();
+    state.push_string(substring)
+}
@@ -1,416 +1,290 @@
-#![allow(warnings)]
-
-use std::{env, fs, collections::HashMap, fmt::Display, path::PathBuf};
-
-type INT_TYPE = i64;
-
-#[derive(Debug, Clone)]
-enum Token {
-    Ident(String),
-    Int(INT_TYPE),
-    Str(String),
-    LParen,
-    RParen,
-    Bang,
-}
-
-fn resolve_includes(mut source_path: PathBuf) -> String {
-    let source = match fs::read_to_string(&source_path) {
-        Ok(source) => source,
-        Err(e) => panic!("ERROR while reading `{}`: {e:?}", source_path.display())
-    };
-    source_path.pop();
-    let dir = source_path;
-    let mut included_source = String::new();
-
-    for line in source.lines() {
-        if line.starts_with("#include ") {
-            let include = PathBuf::from(&line[9..]);
-            let include = dir.join(include);
-
-            included_source.push_str(&resolve_includes(include));
-        } else {
-            included_source.push_str(line);
-            included_source.push('\n');
-        }
-    }
-
-    included_source
-}
-
-fn lex(src: String) -> Vec<Token> {
-    let mut src = src.chars().peekable();
-    let mut tokens = vec![];
-    while let Some(c) = src.next() {
-        match c {
-            '!' if src.peek().copied() != Some('=') => tokens.push(Token::Bang),
-            '(' => tokens.push(Token::LParen),
-            ')' => tokens.push(Token::RParen),
-            '/' if src.peek().copied() == Some('/') => {
-                loop {
-                    if let Some('\n') | None = src.next() {
-                        break;
-                    }
-                }
-            },
-            '"' => {
-                let mut acc = String::new();
-                loop { match src.next() {
-                    Some('"') => break,
-                    Some(c) => acc.push(c),
-                    None => panic!("unfinished string literal"),
-                }}
-
-                tokens.push(Token::Str(unquote(acc)));
+use std::{env, path::PathBuf};
+
+use kcats::{is_instruction, list_instructions, print_completions, render_diff, repl, run_file, run_source, run_stdin, Buffering, Options, RuntimeError, VERSION};
+
+const USAGE: &str = "usage: kcats [OPTION...] [FILE...] [-- ARG...]";
+
+// every option parse_args knows, for --completions
+const FLAGS: &[&str] = &[
+    "--allow-fs",
+    "--assert-empty-at-exit",
+    "--color-diff",
+    "--completions",
+    "--debug",
+    "--deterministic",
+    "--disable-instruction",
+    "--dump-metrics",
+    "--dump-source-map",
+    "--echo-input",
+    "--ensure-final-newline",
+    "--entry",
+    "--fail-fast",
+    "--include-graph",
+    "--info",
+    "--input-timeout",
+    "--json-input",
+    "--list-instructions",
+    "--list-labels",
+    "--lossy-utf8-input",
+    "--max-call-depth",
+    "--max-depth-per-stack",
+    "--max-instructions",
+    "--max-parens-depth",
+    "--max-stacks",
+    "--max-string-len",
+    "--max-value-size",
+    "--max-values",
+    "--no-include",
+    "--output-buffering",
+    "--pretty-stacks",
+    "--profile",
+    "--profile-hot-labels-threshold",
+    "--repl",
+    "--repl-history",
+    "--require-entry",
+    "--sanitize-output",
+    "--self-test",
+    "--summary-on-error",
+    "--summary-size",
+    "--trace",
+    "--trace-depth",
+    "--trace-filter",
+    "--trace-stack-diff",
+    "--version",
+];
+
+// a wrong option is reported like any other error instead of panicking
+fn parse_args(mut args: env::Args) -> Result<(Options, Vec<PathBuf>), String> {
+    let _program = args.next();
+
+    let mut options = Options { warnings: true, ..Options::default() };
+    let mut source_paths = Vec::new();
+
+    while let Some(arg) = args.next() {
+        let mut value = |flag: &str| match args.next() {
+            Some(value) => Ok(value),
+            None => Err(format!("missing value for option `{flag}`")),
+        };
+
+        match arg.as_str() {
+            "--" => {
+                options.args = args.by_ref().collect();
+                break;
             }
-            '0'..='9' => {
-                let mut acc = String::new();
-                acc.push(c);
-                while let Some(c @ '0'..='9') = src.next() {
-                    acc.push(c);
+            "--allow-fs" => options.capabilities.files = true,
+            "--assert-empty-at-exit" => options.assert_empty_at_exit = true,
+            "--color-diff" => options.color_diff = true,
+            "--completions" => options.completions = true,
+            "--debug" => options.debug = true,
+            "--deterministic" => options.deterministic = true,
+            "--disable-instruction" => {
+                let name = value(&arg)?;
+                if !is_instruction(&name) {
+                    return Err(format!("cannot disable unknown instruction `{name}`"));
                 }
-
-                tokens.push(Token::Int(acc.parse().unwrap()));
-            },
-            ' ' | '\n' | '\t' => (),
-            c => {
-                let mut acc = String::new();
-                acc.push(c);
-                loop { match src.next() {
-                    Some(' ' | '\n' | '\t' | '"' | '(' | ')') | None => break,
-                    Some(c) => acc.push(c),
-                }}
-                tokens.push(Token::Ident(acc));
+                options.capabilities.disabled_instructions.push(name);
             }
+            "--dump-metrics" => match value(&arg)?.as_str() {
+                "json" => options.dump_metrics = true,
+                format => return Err(format!("unknown metrics format `{format}`, expected `json`")),
+            },
+            "--dump-source-map" => options.dump_source_map = true,
+            "--echo-input" => options.echo_input = true,
+            "--entry" => options.entry = Some(value(&arg)?),
+            "--ensure-final-newline" => options.ensure_final_newline = true,
+            "--include-graph" => options.include_graph = true,
+            "--fail-fast" => options.fail_fast = true,
+            "--info" => options.info = true,
+            "--input-timeout" => options.input_timeout = Some(parse_option_value(&arg, value(&arg)?)?),
+            "--list-instructions" => options.list_instructions = true,
+            "--list-labels" => options.list_labels = true,
+            "--json-input" => options.json_input = true,
+            "--lossy-utf8-input" => options.lossy_utf8_input = true,
+            "--output-buffering" => options.output_buffering = match value(&arg)?.as_str() {
+                "line" => Buffering::Line,
+                "block" => Buffering::Block,
+                "none" => Buffering::None,
+                mode => return Err(format!("unknown output buffering `{mode}`, expected `line`, `block` or `none`")),
+            },
+            "--no-include" => options.capabilities.includes = false,
+            "--pretty-stacks" => options.pretty_stacks = true,
+            "--repl" => options.repl = true,
+            "--repl-history" => options.repl_history = Some(PathBuf::from(value(&arg)?)),
+            "--require-entry" => options.require_entry = true,
+            "--sanitize-output" => options.sanitize_output = true,
+            "--self-test" => options.self_test = true,
+            "--summary-on-error" => options.summary_on_error = true,
+            "--summary-size" => options.summary_size = Some(parse_option_value(&arg, value(&arg)?)?),
+            "--version" => options.version = true,
+            "--trace" => options.trace = true,
+            "--trace-filter" => options.trace_filter = Some(value(&arg)?.split(',').map(str::to_string).collect()),
+            "--trace-stack-diff" => options.trace_stack_diff = true,
+            "--trace-depth" => options.trace_depth = Some(parse_option_value(&arg, value(&arg)?)?),
+            "--profile" => options.profile = true,
+            "--profile-hot-labels-threshold" => options.hot_labels_threshold = Some(parse_option_value(&arg, value(&arg)?)?),
+            "--max-call-depth" => options.max_call_depth = Some(parse_option_value(&arg, value(&arg)?)?),
+            "--max-depth-per-stack" => options.max_depth_per_stack = Some(parse_option_value(&arg, value(&arg)?)?),
+            "--max-instructions" => options.max_instructions = Some(parse_option_value(&arg, value(&arg)?)?),
+            "--max-parens-depth" => options.max_parens_depth = Some(parse_option_value(&arg, value(&arg)?)?),
+            "--max-stacks" => options.max_stacks = Some(parse_option_value(&arg, value(&arg)?)?),
+            "--max-string-len" => options.max_string_len = Some(parse_option_value(&arg, value(&arg)?)?),
+            "--max-value-size" => options.max_value_size = Some(parse_option_value(&arg, value(&arg)?)?),
+            "--max-values" => options.max_values = Some(parse_option_value(&arg, value(&arg)?)?),
+            flag if flag.starts_with("--") => return Err(format!("unknown option `{flag}`")),
+            _ => source_paths.push(PathBuf::from(arg)),
         }
     }
 
-    tokens
+    Ok((options, source_paths))
 }
 
-fn unquote(mut text: String) -> String {
-    text.replace("\\n", "\n")
-        .replace("\\t", "\t")
+fn parse_option_value<T: std::str::FromStr>(flag: &str, value: String) -> Result<T, String> {
+    match value.parse() {
+        Ok(value) => Ok(value),
+        Err(_) => Err(format!("invalid value `{value}` for option `{flag}`")),
+    }
 }
 
-
 fn main() {
-    let mut args = env::args();
-    let _program = args.next().unwrap();
-    
-    let source = resolve_includes(args.next().expect("please provide a source file").into());
-    let tokens = lex(source);
-
-    let mut state = State {
-        pc: 0,
-        current_stack: 0,
-        stacks: vec![Vec::new()],
-        labels: HashMap::new(),
-    };
-
-    while state.pc < tokens.len() {
-        if let Token::Ident(ref instr) = tokens[state.pc] {
-            if instr.starts_with(':') {
-                state.labels.insert(instr[1..].to_string(), state.pc);
-            }
+    let (options, mut source_paths) = match parse_args(env::args()) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            eprintln!("error: {e}");
+            eprintln!("{USAGE}");
+            std::process::exit(2);
         }
-        state.pc += 1;
-    }
-
-    state.pc = 0;
-    while state.pc < tokens.len() {
-        match tokens[state.pc] {
-            Token::LParen | Token::RParen => (),
-            Token::Bang => i_save_pc(&mut state),
-            Token::Int(i) => state.push_int(i),
-            Token::Str(ref s) => state.push_string(s.to_string()),
-            Token::Ident(ref instr) => match instr.as_str() {
-                "+"   => i_add(&mut state),
-                "-"   => i_sub(&mut state),
-                "*"   => i_mul(&mut state),
-                "/"   => i_div(&mut state),
-                "%"   => i_mod(&mut state),
-                "=="  => i_eq(&mut state),
-                "!="  => i_neq(&mut state),
-                "=>"  => i_copy_right(&mut state),
-                "<="  => i_copy_left(&mut state),
-                "."  => i_get(&mut state),
-                "->"  => state.switch_to_right_stack(),
-                "<-"  => state.switch_to_left_stack(),
-                "chr" => i_chr(&mut state),
-                "concat" => i_concat(&mut state),
-                "debug" => eprintln!("{state}"),
-                "dup" => i_dup(&mut state),
-                "empty" => i_empty(&mut state),
-                "input" => i_input(&mut state),
-                "goto_if" => i_goto_if(&mut state),
-                "jump" => i_jump(&mut state),
-                "jump_if" => i_jump_if(&mut state),
-                "len" => i_len(&mut state),
-                "pop" => i_pop(&mut state),
-                "print" => i_print(&mut state),
-                instr if instr.starts_with(':') => (),
-                instr => match state.labels.get(instr) {
-                    Some(pc) => state.pc = *pc,
-                    None => panic!("unknown instruction `{instr}`"),
-                }
-            }
-        }
-        state.pc += 1;
-    }
-}
-
-#[derive(Debug)]
-struct State {
-    pc: usize,
-    current_stack: usize,
-    stacks: Vec<Vec<String>>,
-    labels: HashMap<String, usize>,
-}
-
-impl Display for State {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{{ pc: {}, current_stack: {}, stacks: {:?} }}",
-            self.pc, self.current_stack, self.stacks
-        )
-    }
-}
-
-impl Drop for State {
-    fn drop(&mut self) {
-        //eprintln!("Exiting state: {self}");
-    }
-}
+    };
 
-impl State {
-    fn push_string(&mut self, value: String) {
-        self.stacks[self.current_stack].push(value);
+    if options.version {
+        println!("kcats {VERSION}");
+        return;
     }
 
-    fn push_int(&mut self, value: INT_TYPE) {
-        self.stacks[self.current_stack].push(format!("{}", value));
+    if options.list_instructions {
+        list_instructions();
+        return;
     }
 
-    fn pop_string(&mut self) -> String {
-        self.stacks[self.current_stack]
-            .pop()
-            .expect("failed to pop string from stack")
+    if options.completions {
+        print_completions(FLAGS);
+        return;
     }
 
-    fn pop_int(&mut self) -> INT_TYPE {
-        self.stacks[self.current_stack]
-            .pop()
-            .expect("failed to pop int from stack")
-            .parse::<INT_TYPE>()
-            .expect("failed to convert value to int")
+    if options.self_test {
+        std::process::exit(run_self_test(options));
     }
 
-    fn switch_to_left_stack(&mut self) {
-        if self.current_stack == 0 {
-            panic!("attempted to switch to the left stack from the leftmost stack")
+    if options.repl {
+        if let Err(e) = repl(options) {
+            eprintln!("error: {e}");
+            std::process::exit(1);
         }
-
-        self.current_stack -= 1;
+        return;
     }
 
-    fn switch_to_right_stack(&mut self) {
-        self.current_stack += 1;
-
-        if self.current_stack == self.stacks.len() {
-            self.stacks.push(Vec::new());
+    // without a path, or with `-`, the program is read from stdin
+    let result = match source_paths.len() {
+        0 => run_stdin(options),
+        1 if source_paths[0].as_os_str() == "-" => run_stdin(options),
+        1 => run_file(source_paths.pop().unwrap(), options),
+        _ => std::process::exit(run_batch(source_paths, options)),
+    };
+    match result {
+        Ok(0) => {}
+        Ok(code) => std::process::exit(code),
+        Err(e) => {
+            eprintln!("error: {e}");
+            std::process::exit(1);
         }
     }
 }
 
-// INSTRUCTIONS
-
-// $x $y +
-fn i_add(state: &mut State) {
-    let y = state.pop_int();
-    let x = state.pop_int();
-
-    state.push_int(x+y);
-}
-
-// $code chr
-fn i_chr(state: &mut State) {
-    let code = state.pop_int();
-
-    let mut out = String::with_capacity(1);
-
-    out.push(match char::from_u32(code as u32) {
-        Some(c) => c,
-        None => panic!("`{}` is not a valid character", code),
-    });
-
-    state.push_string(out);
-}
-
-// $start $end concat
-fn i_concat(state: &mut State) {
-    let end = state.pop_string();
-    let mut start = state.pop_string();
-
-    start.push_str(&end);
-
-    state.push_string(start);
-}
-
-// $value <=
-fn i_copy_left(state: &mut State) {
-    let value = state.pop_string();
-    
-    state.switch_to_left_stack();
-    state.push_string(value);
-    state.switch_to_right_stack();
-}
-
-// $value =>
-fn i_copy_right(state: &mut State) {
-    let value = state.pop_string();
-    
-    state.switch_to_right_stack();
-    state.push_string(value);
-    state.switch_to_left_stack();
-}
-
-// $dividend $divisor /
-fn i_div(state: &mut State) {
-    let divisor = state.pop_int();
-    let dividend = state.pop_int();
-
-    state.push_int(dividend/dividend);
-}
-
-// $value dup
-fn i_dup(state: &mut State) {
-    let value = state.pop_string();
-
-    state.push_string(value.clone());
-    state.push_string(value);
-}
-
-// empty
-fn i_empty(state: &mut State) {
-    state.push_int(state.stacks[state.current_stack].is_empty() as INT_TYPE)
-}
-
-// input
-fn i_input(state: &mut State) {
-    use std::io;
-    let mut value = String::new();
-
-    io::stdin().read_line(&mut value);
-    value.pop();
-
-    state.push_string(value);
-}
-
-// $x $y ==
-fn i_eq(state: &mut State) {
-    let y = state.pop_string();
-    let x = state.pop_string();
-
-    state.push_int((x == y) as INT_TYPE);
-}
-
-// $string $index .
-fn i_get(state: &mut State) {
-    let index = state.pop_int();
-    let string = state.pop_string();
-
-    let mut c = String::with_capacity(1);
-    c.push(match string.chars().nth(index as usize) {
-        Some(val) => val,
-        None => panic!("attempted to access string {string:?} with an invalid index: {index}"),
-    });
-
-    state.push_string(c);
-}
- 
-// $condition $label goto_if
-fn i_goto_if(state: &mut State) {
-    let label = state.pop_string();
-    let condition = state.pop_int();
-
-    if condition != 0 {
-        state.pc = match state.labels.get(&label) {
-            Some(pc) => *pc,
-            None => panic!("unknown label `{label}`"),
+// runs each file in a fresh state, returning the exit code
+fn run_batch(source_paths: Vec<PathBuf>, options: Options) -> i32 {
+    let mut failures = 0;
+    for source_path in source_paths {
+        let name = source_path.display().to_string();
+
+        let passed = run_reported(&name, || match run_file(source_path, options.clone())? {
+            0 => Ok(()),
+            code => Err(RuntimeError::Other(format!("exited with code {code}"))),
+        });
+        if !passed {
+            failures += 1;
+            if options.fail_fast {
+                break;
+            }
         }
     }
-}
-
-// $pc jump
-fn i_jump(state: &mut State) {
-    let pc = state.pop_int();
-
-    state.pc = pc as usize;
-}
-
-// $condition $pc jump_if
-fn i_jump_if(state: &mut State) {
-    let pc = state.pop_int();
-    let condition = state.pop_int();
-
-    if condition != 0 {
-        state.pc = pc as usize;
-    }
-}
-
-// $string len
-fn i_len(state: &mut State) {
-    let string = state.pop_string();
 
-    state.push_int(string.len() as INT_TYPE);
+    eprintln!("{failures} failed");
+    (failures != 0) as i32
 }
 
-// $x $y *
-fn i_mul(state: &mut State) {
-    let y = state.pop_int();
-    let x = state.pop_int();
-
-    state.push_int(x*y);
-}
-
-// $value $modulo %
-fn i_mod(state: &mut State) {
-    let modulo = state.pop_int();
-    let value = state.pop_int();
-
-    state.push_int(value % modulo)
-}
+// name, source and expected output, if it is checked
+const SELF_TESTS: &[(&str, &str, Option<&str>)] = &[
+    ("arithmetic.kc", include_str!("../selftest/arithmetic.kc"), None),
+    ("comparison.kc", include_str!("../selftest/comparison.kc"), None),
+    ("control.kc", include_str!("../selftest/control.kc"), None),
+    ("halt.kc", include_str!("../selftest/halt.kc"), Some(include_str!("../selftest/halt.out"))),
+    ("output.kc", include_str!("../selftest/output.kc"), Some(include_str!("../selftest/output.out"))),
+    ("stacks.kc", include_str!("../selftest/stacks.kc"), None),
+    ("strings.kc", include_str!("../selftest/strings.kc"), None),
+];
+
+// runs the bundled fixtures, returning the exit code
+fn run_self_test(options: Options) -> i32 {
+    let color = options.color_diff && io_is_colored();
+
+    let mut failures = 0;
+    for (name, source, expected) in SELF_TESTS {
+        let mut test_options = options.clone();
+        test_options.capture_output = expected.is_some();
+
+        let passed = run_reported(name, || {
+            let finished = run_source(name, source, test_options)?;
+            if finished.exit_code != 0 {
+                return Err(RuntimeError::Other(format!("exited with code {}", finished.exit_code)));
+            }
 
-// $x $y !=
-fn i_neq(state: &mut State) {
-    let x = state.pop_string();
-    let y = state.pop_string();
+            if let Some(expected) = expected {
+                if finished.output != *expected {
+                    let diff = render_diff(expected, &finished.output, color);
+                    return Err(RuntimeError::Other(format!("output differs from the expected output:\n{diff}")));
+                }
+            }
 
-    state.push_int((x != y) as INT_TYPE);
-}
+            Ok(())
+        });
+        if !passed {
+            failures += 1;
+            if options.fail_fast {
+                break;
+            }
+        }
+    }
 
-// $value print
-fn i_pop(state: &mut State) {
-    state.pop_string();
+    eprintln!("{failures} of {} self-tests failed", SELF_TESTS.len());
+    (failures != 0) as i32
 }
 
-// $value print
-fn i_print(state: &mut State) {
-    use std::io::{self, Write};
+// colors go to stderr, and are left out for pipes and when NO_COLOR is set
+fn io_is_colored() -> bool {
+    use std::io::{self, IsTerminal};
 
-    print!("{}", state.pop_string());
-    io::stdout().flush().unwrap()
+    io::stderr().is_terminal() && env::var_os("NO_COLOR").is_none()
 }
 
-// !
-fn i_save_pc(state: &mut State) {
-    state.push_int(state.pc as INT_TYPE);
-}
-
-// $base $by -
-fn i_sub(state: &mut State) {
-    let by = state.pop_int();
-    let base = state.pop_int();
-
-    state.push_int(base-by);
+// reports whether the run succeeded, printing the error instead of stopping
+fn run_reported(name: &str, run: impl FnOnce() -> Result<(), RuntimeError>) -> bool {
+    match run() {
+        Ok(()) => {
+            eprintln!("ok: {name}");
+            true
+        }
+        Err(e) => {
+            eprintln!("FAILED: {name}: {e}");
+            false
+        }
+    }
 }
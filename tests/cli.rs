@@ -0,0 +1,549 @@
+// runs the kcats binary the way it is used from a shell, for what kcats::run can't show:
+// flags, stderr, stdin and files
+
+use std::{io::Write, path::Path, process::{Command, Output, Stdio}};
+
+// from tests/, so the files these tests use are under `cli/`, with `stdin` piped in
+fn kcats(args: &[&str], stdin: impl AsRef<[u8]>) -> Output {
+    kcats_in(&Path::new(env!("CARGO_MANIFEST_DIR")).join("tests"), args, stdin)
+}
+
+fn kcats_in(dir: &Path, args: &[&str], stdin: impl AsRef<[u8]>) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_kcats"))
+        .args(args)
+        .current_dir(dir)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(stdin.as_ref()).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+#[test]
+fn parens_depth_limit() {
+    let output = kcats(&["--max-parens-depth", "2"], "( ( 1 ) )");
+    assert!(output.status.success());
+
+    let output = kcats(&["--max-parens-depth", "2"], "( ( ( 1 ) ) )");
+    assert_eq!(stderr(&output), "error: <stdin>:1:5: parentheses are nested deeper than the maximum depth of 2\n");
+}
+
+#[test]
+fn tags_show_in_debug() {
+    let output = kcats(&[], "\"x\" dup \"first\" tag debug");
+    assert_eq!(stderr(&output), "{ pc: 4, current_stack: 0, stacks: [[\"x\", \"x\" #first]] }\n");
+}
+
+#[test]
+fn defines() {
+    let output = kcats(&["cli/define.kc"], "");
+    assert_eq!(stdout(&output), "30\nBASE\n");
+
+    let output = kcats(&["cli/redefine.kc"], "");
+    assert!(!output.status.success());
+    assert_eq!(stderr(&output), "error: cli/redefine.kc:2: `BASE` is already defined at cli/consts.kc:1\n");
+}
+
+#[test]
+fn program_from_stdin() {
+    let output = kcats(&[], "\"from stdin\\n\" print 2 3 + print");
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "from stdin\n5");
+
+    let output = kcats(&["-"], "input");
+    assert_eq!(stderr(&output), "error: <stdin>:1:1: there is no input to read, stdin was read as the program\n");
+}
+
+#[test]
+fn max_call_depth() {
+    let recursion = "\"f\" call\n:f \"f\" call";
+
+    let output = kcats(&["--max-call-depth", "3"], recursion);
+    assert_eq!(stderr(&output), "\
+error: <stdin>:2:8: call stack overflow: exceeded the maximum call depth of 3 while calling `f`
+    in `f`, called at <stdin>:2:8
+    ... 1 more frame like the one above
+    in `f`, called at <stdin>:1:5
+");
+}
+
+#[test]
+fn trace_filter() {
+    // counts to 3, jumping back to the index saved by `!`
+    let program = "0 ! swap 1 + dup 3 == \"end\" goto_if swap dup jump\n:end pop pop";
+
+    let output = kcats(&["--trace", "--trace-filter", "jump"], program);
+    assert_eq!(stderr(&output), "12: jump  [[\"1\", \"1\"]]\n12: jump  [[\"2\", \"1\"]]\n");
+
+    let output = kcats(&["--trace", "--trace-filter", "end"], program);
+    assert_eq!(stderr(&output), "9: goto_if  [[\"1\", \"3\"]]\n");
+}
+
+// empty, and only used by the test of that name
+fn temp_dir(name: &str) -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(format!("kcats-{name}-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[test]
+fn read_file_and_write_file() {
+    let dir = temp_dir("read_file_and_write_file");
+    let path = dir.join("note.txt");
+    let program = "argv pop dup \"written by kcats\" swap write_file read_file print";
+
+    let output = kcats(&["--allow-fs", "-", "--", path.to_str().unwrap()], program);
+    assert_eq!(stderr(&output), "");
+    assert_eq!(stdout(&output), "written by kcats");
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "written by kcats");
+
+    let output = kcats(&["-", "--", path.to_str().unwrap()], program);
+    assert_eq!(stderr(&output), "error: <stdin>:1:38: `write_file` is only available with --allow-fs\n");
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn input_timeout() {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_kcats"))
+        .args(["--input-timeout", "100", "cli/input.kc"])
+        .current_dir(Path::new(env!("CARGO_MANIFEST_DIR")).join("tests"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    // kept open without writing anything, so that the read has to time out
+    let stdin = child.stdin.take();
+
+    let output = child.wait_with_output().unwrap();
+    drop(stdin);
+    assert_eq!(stderr(&output), "error: cli/input.kc:1:1: input timeout: nothing was read within 100 ms\n");
+}
+
+#[test]
+fn circular_include() {
+    let output = kcats(&["cli/cycle_a.kc"], "");
+    assert_eq!(stderr(&output), "error: circular include detected: cli/cycle_a.kc -> cli/cycle_b.kc -> cli/cycle_a.kc\n");
+}
+
+#[test]
+fn includes_from_another_directory() {
+    let dir = temp_dir("includes_from_another_directory");
+    let program = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/cli/define.kc");
+
+    // consts.kc is found next to define.kc, not in the directory kcats runs in
+    let output = kcats_in(&dir, &[program.to_str().unwrap()], "");
+    assert_eq!(stderr(&output), "");
+    assert_eq!(stdout(&output), "30\nBASE\n");
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn bad_options_are_usage_errors() {
+    let output = kcats(&["--no-such-flag"], "");
+    assert_eq!(output.status.code(), Some(2));
+    assert_eq!(stderr(&output), "error: unknown option `--no-such-flag`\nusage: kcats [OPTION...] [FILE...] [-- ARG...]\n");
+
+    let output = kcats(&["--max-instructions", "many"], "");
+    assert_eq!(output.status.code(), Some(2));
+    assert!(stderr(&output).starts_with("error: invalid value `many` for option `--max-instructions`\n"));
+
+    let output = kcats(&["--entry"], "");
+    assert_eq!(output.status.code(), Some(2));
+    assert!(stderr(&output).starts_with("error: missing value for option `--entry`\n"));
+}
+
+#[test]
+fn arguments_after_double_dash_are_argv() {
+    let output = kcats(&["cli/argv.kc", "--", "hello", "42"], "");
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "242hello");
+
+    let output = kcats(&["cli/argv.kc"], "");
+    assert_eq!(stdout(&output), "0");
+}
+
+#[test]
+fn batch_reports_each_file() {
+    let output = kcats(&["cli/pass.kc", "cli/fail.kc"], "");
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(stdout(&output), "pass");
+    assert_eq!(
+        stderr(&output),
+        "ok: cli/pass.kc\nFAILED: cli/fail.kc: cli/fail.kc:1:5: division by zero in `/`: 1 / 0\n1 failed\n"
+    );
+
+    let output = kcats(&["cli/pass.kc", "cli/pass.kc"], "");
+    assert!(output.status.success());
+    assert!(stderr(&output).ends_with("0 failed\n"));
+}
+
+#[test]
+fn fail_fast_stops_at_the_first_failure() {
+    let output = kcats(&["cli/fail.kc", "cli/type_error.kc"], "");
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("FAILED: cli/type_error.kc"));
+    assert!(stderr(&output).ends_with("2 failed\n"));
+
+    let output = kcats(&["--fail-fast", "cli/fail.kc", "cli/type_error.kc"], "");
+    assert_eq!(output.status.code(), Some(1));
+    assert!(!stderr(&output).contains("type_error.kc"));
+    assert!(stderr(&output).ends_with("1 failed\n"));
+}
+
+#[test]
+fn json_input() {
+    let output = kcats(&["--json-input", "cli/json.kc"], r#"{"a": {"b": [1, "two"], "c": true}}"#);
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), r#"two 1 {"a":{"b":[1,"two"],"c":true}}"#);
+
+    let output = kcats(&["--json-input", "cli/json.kc"], format!("{}{}", "[".repeat(129), "]".repeat(129)));
+    assert_eq!(
+        stderr(&output),
+        "error: --json-input: stdin is not valid JSON: arrays and objects are too deeply nested, more than 128 levels\n"
+    );
+}
+
+#[test]
+fn invalid_utf8_input() {
+    for timeout in [&[][..], &["--input-timeout", "5000"]] {
+        let output = kcats(&[timeout, &["cli/input.kc"]].concat(), b"ok \xff\n");
+        assert_eq!(stderr(&output), "error: cli/input.kc:1:1: input is not valid UTF-8 at byte 3\n");
+
+        let output = kcats(&[timeout, &["--lossy-utf8-input", "cli/input.kc"]].concat(), b"ok \xff\n");
+        assert!(output.status.success());
+        assert_eq!(stdout(&output), "ok \u{fffd}");
+    }
+}
+
+#[test]
+fn self_test_passes() {
+    let output = kcats(&["--self-test"], "");
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).ends_with("0 of 7 self-tests failed\n"));
+}
+
+#[test]
+fn fill_over_the_depth_limit_keeps_the_named_stack() {
+    let output = kcats(&["--max-depth-per-stack", "4", "cli/fill_limit.kc"], "");
+    assert_eq!(stdout(&output), "3");
+    assert!(stderr(&output).starts_with("error: cli/fill_limit.kc:8:17: stack 0 would exceed the maximum depth of 4 values\n"));
+}
+
+#[test]
+fn invalid_utf8_read_all_and_read_byte() {
+    let output = kcats(&["cli/read_all.kc"], b"ok \xff");
+    assert_eq!(stderr(&output), "error: cli/read_all.kc:1:1: input is not valid UTF-8 at byte 3\n");
+
+    let output = kcats(&["--lossy-utf8-input", "cli/read_all.kc"], b"ok \xff");
+    assert_eq!(stdout(&output), "ok \u{fffd}");
+
+    // bytes are never decoded, so both modes see the invalid one as it is
+    for lossy in [&[][..], &["--lossy-utf8-input"]] {
+        let output = kcats(&[lossy, &["cli/read_byte.kc"]].concat(), b"\xffa");
+        assert!(output.status.success());
+        assert_eq!(stdout(&output), "255 97 -1");
+    }
+}
+
+#[test]
+fn last_line_and_input_eof() {
+    // the last line keeps its final character without a newline after it
+    let output = kcats(&["cli/input_eof.kc"], "a\nb");
+    assert_eq!(stdout(&output), "a|0|b|0||1");
+
+    let output = kcats(&["cli/input_eof.kc"], "a\n");
+    assert_eq!(stdout(&output), "a|0||1||1");
+}
+
+#[test]
+fn require_version() {
+    let output = kcats(&["cli/require_newer.kc"], "");
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(stdout(&output), "");
+    assert_eq!(stderr(&output), format!("error: cli/require_newer.kc:1: requires kcats 99.0, but this is kcats {}\n", env!("CARGO_PKG_VERSION")));
+
+    let output = kcats(&["cli/require_older.kc"], "");
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "ran");
+
+    let dir = temp_dir("require_version");
+    let path = dir.join("current.kc");
+    std::fs::write(&path, format!("#require_version {}\n\"ran\" print\n", env!("CARGO_PKG_VERSION"))).unwrap();
+    let output = kcats(&[path.to_str().unwrap()], "");
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "ran");
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn include_if_exists() {
+    let output = kcats(&["cli/optional_include.kc"], "");
+    assert_eq!(stderr(&output), "");
+    assert_eq!(stdout(&output), "hi from helpers");
+}
+
+#[test]
+fn profile_counts() {
+    let output = kcats(&["--profile", "cli/count_down.kc"], "");
+    assert_eq!(stderr(&output), "profile: -: 3, :loop: 3, dup: 3, goto_if: 3, pop: 1\n");
+}
+
+#[test]
+fn include_graph_of_a_diamond() {
+    // base.kc is reached through both left.kc and right.kc, which is not a cycle
+    let output = kcats(&["--include-graph", "cli/diamond/top.kc"], "");
+    assert!(output.status.success());
+    assert_eq!(
+        stdout(&output),
+        "cli/diamond/top.kc\n  cli/diamond/left.kc\n    cli/diamond/base.kc\n  cli/diamond/right.kc\n    cli/diamond/base.kc\n"
+    );
+}
+
+#[test]
+fn source_map_of_nested_includes() {
+    let output = kcats(&["--dump-source-map", "cli/nested/outer.kc"], "");
+    assert!(output.status.success());
+    assert_eq!(
+        stdout(&output),
+        "1-1 cli/nested/outer.kc:1-1\n\
+         2-2 cli/nested/middle.kc:1-1\n\
+         3-4 cli/nested/inner.kc:1-2\n\
+         5-5 cli/nested/middle.kc:3-3\n\
+         6-6 cli/nested/outer.kc:3-3\n"
+    );
+}
+
+#[test]
+fn src_file_and_src_line_in_an_included_file() {
+    let output = kcats(&["cli/where.kc"], "");
+    assert_eq!(stdout(&output), "cli/where.kc:1\ncli/where_inner.kc:3\ncli/where.kc:3\n");
+}
+
+#[test]
+fn for_each_line_calls_the_label_per_line() {
+    let output = kcats(&["cli/each_line.kc"], "one\ntwo\nthree\n");
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "3 one;3 two;5 three;done");
+}
+
+#[test]
+fn trace_stack_diff_of_an_addition() {
+    let output = kcats(&["--trace-stack-diff", "-"], "2 1 +");
+    assert!(output.status.success());
+    // `+` pops both operands and pushes their sum
+    assert_eq!(stderr(&output), "0: 2  -[] +[\"2\"]\n1: 1  -[] +[\"1\"]\n2: +  -[\"2\", \"1\"] +[\"3\"]\n");
+}
+
+#[test]
+fn ensure_final_newline() {
+    let output = kcats(&["--ensure-final-newline", "-"], "\"no newline\" print");
+    assert_eq!(stdout(&output), "no newline\n");
+
+    let output = kcats(&["--ensure-final-newline", "-"], "\"newline\\n\" print");
+    assert_eq!(stdout(&output), "newline\n");
+
+    let output = kcats(&["--ensure-final-newline", "-"], "");
+    assert_eq!(stdout(&output), "");
+}
+
+#[test]
+fn hot_label_hint() {
+    let output = kcats(&["--profile-hot-labels-threshold", "50", "cli/count_down.kc"], "");
+    assert_eq!(
+        stderr(&output),
+        "hint: label `:loop` ran 3 times and accounts for 88.9% of executed instructions — consider hoisting invariant work out of it\n"
+    );
+
+    let output = kcats(&["--profile-hot-labels-threshold", "95", "cli/count_down.kc"], "");
+    assert_eq!(stderr(&output), "");
+}
+
+#[test]
+fn listings_are_stable() {
+    let labels = kcats(&["--list-labels", "../selftest/control.kc"], "");
+    assert_eq!(labels.stdout, kcats(&["--list-labels", "../selftest/control.kc"], "").stdout);
+    let lines: Vec<_> = stdout(&labels).lines().map(|line| line.split_once(":").unwrap().1.parse::<usize>().unwrap()).collect();
+    assert!(lines.len() > 1 && lines.is_sorted(), "labels are listed in source order");
+
+    let instructions = kcats(&["--list-instructions"], "");
+    assert_eq!(instructions.stdout, kcats(&["--list-instructions"], "").stdout);
+    let names: Vec<_> = stdout(&instructions).lines().map(str::to_string).collect();
+    assert!(names.len() > 1 && names.is_sorted(), "instructions are listed by name");
+}
+
+#[test]
+fn completions_list_instructions_directives_and_flags() {
+    let output = kcats(&["--completions"], "");
+    let words: Vec<_> = stdout(&output).lines().map(str::to_string).collect();
+    for word in ["dup", "print", "#include", "#define", "--trace", "--max-call-depth"] {
+        assert!(words.iter().any(|w| w == word), "`{word}` is missing from the completions");
+    }
+
+    // each flag is one kcats knows, at worst missing its value
+    for flag in words.iter().filter(|word| word.starts_with("--")) {
+        let output = kcats(&[flag], "");
+        assert!(!stderr(&output).contains("unknown option"), "{flag}: {}", stderr(&output));
+    }
+}
+
+#[test]
+fn echo_input() {
+    // the line read is echoed to stdout, before the program prints it
+    let output = kcats(&["--echo-input", "cli/input.kc"], "hello\n");
+    assert_eq!(stdout(&output), "hello\nhello");
+
+    let output = kcats(&["cli/input.kc"], "hello\n");
+    assert_eq!(stdout(&output), "hello");
+}
+
+#[test]
+fn pretty_stacks() {
+    let output = kcats(&["--pretty-stacks", "-"], "1 \"two\" \"x\" \"t\" tag -> 3 <- debug");
+    assert_eq!(stderr(&output), "pc: 8\nstack 0 (current):\n  \"1\"\n  \"two\"\n  \"x\" #t\nstack 1:\n  \"3\"\n");
+}
+
+#[test]
+fn no_include_rejects_includes() {
+    let output = kcats(&["--no-include", "cli/define.kc"], "");
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(stdout(&output), "");
+    assert_eq!(stderr(&output), "error: `#include` is disabled, but cli/define.kc:3 includes `consts.kc`\n");
+}
+
+#[test]
+fn dump_metrics_json() {
+    let output = kcats(&["--dump-metrics", "json", "cli/metrics.kc"], "");
+    assert!(output.status.success());
+    // the wall time is the only key that changes from run to run
+    let stderr = stderr(&output);
+    let (start, rest) = stderr.split_once("\"wall_time_ms\": ").unwrap();
+    assert_eq!(start, "{\"instructions\": 10, \"peak_depth\": 3, \"peak_memory_bytes\": 5, ");
+    let (wall_time, end) = rest.split_once(", ").unwrap();
+    assert!(wall_time.parse::<f64>().is_ok(), "{wall_time}");
+    assert_eq!(end, "\"label_hits\": {\"f\": 2}}\n");
+}
+
+#[test]
+fn entry_labels() {
+    let output = kcats(&["cli/entry.kc"], "");
+    assert_eq!(stdout(&output), "top");
+
+    let output = kcats(&["--entry", "other", "cli/entry.kc"], "");
+    assert_eq!(stdout(&output), "other");
+
+    let output = kcats(&["--require-entry", "cli/entry.kc"], "");
+    assert_eq!(stdout(&output), "main");
+
+    let output = kcats(&["--require-entry", "cli/count_down.kc"], "");
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(stderr(&output), "error: no entry label `main`, required by --require-entry\n");
+}
+
+#[test]
+fn output_buffering_keeps_stdout_before_stderr() {
+    let program = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/cli/buffering.kc");
+    for mode in ["line", "block", "none"] {
+        // both streams into one pipe, to see which came first
+        let output = Command::new("sh")
+            .arg("-c")
+            .arg(format!("\"$0\" --output-buffering {mode} \"$1\" 2>&1"))
+            .arg(env!("CARGO_BIN_EXE_kcats"))
+            .arg(&program)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        assert_eq!(stdout(&output), "a{ pc: 2, current_stack: 0, stacks: [[]] }\nb", "--output-buffering {mode}");
+    }
+}
+
+#[test]
+fn die_stops_with_a_message() {
+    let output = kcats(&["-"], "\"partial\" print \"config is missing\" die \"never\" print");
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(stdout(&output), "partial");
+    assert_eq!(stderr(&output), "config is missing\n");
+}
+
+#[test]
+fn trace_depth_hides_deeper_values() {
+    let output = kcats(&["--trace", "--trace-depth", "2", "-"], "1 2 3 4");
+    assert_eq!(
+        stderr(&output),
+        "0: 1  [[\"1\"]]\n1: 2  [[\"1\", \"2\"]]\n2: 3  [[..., \"2\", \"3\"]]\n3: 4  [[..., \"3\", \"4\"]]\n"
+    );
+}
+
+#[test]
+fn disabled_instruction() {
+    let output = kcats(&["--disable-instruction", "print", "-"], "1 2 + print");
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(stdout(&output), "");
+    assert_eq!(stderr(&output), "error: <stdin>:1:7: instruction `print` is disabled\n");
+
+    let output = kcats(&["--disable-instruction", "print", "-"], "1 2 +");
+    assert!(output.status.success());
+}
+
+#[test]
+fn info_prints_meta_directives() {
+    let output = kcats(&["--info", "cli/meta.kc"], "");
+    assert!(output.status.success());
+    // the directives are printed instead of running the program
+    assert_eq!(stdout(&output), "name\tCount down\nauthor\tsomeone\n");
+}
+
+#[test]
+fn raw_jumps_are_flagged() {
+    let output = kcats(&["-"], "\"a\" print 5 jump \"b\" print \"c\" print");
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "ac");
+    assert_eq!(
+        stderr(&output),
+        "warning: <stdin>:1:11: `jump` to the raw token index 5 breaks when includes change; jump to a label, or to an index saved by `!`\n"
+    );
+
+    // a loop on a label keeps working with an include above it, and isn't flagged
+    let output = kcats(&["cli/include_loop.kc"], "");
+    assert_eq!(stdout(&output), "123");
+    assert_eq!(stderr(&output), "");
+}
+
+#[test]
+fn summary_on_error() {
+    let output = kcats(&["--summary-on-error", "--summary-size", "3", "-"], "1 2 +\n\"x\" 0 /\n");
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(
+        stderr(&output),
+        "last 3 instructions, up to the one that failed:\n\
+         \x20   3: \"x\"  at <stdin>:2:1\n\
+         \x20   4: 0  at <stdin>:2:5\n\
+         \x20   5: /  at <stdin>:2:7\n\
+         error: <stdin>:2:7: failed to convert value to int: `x`\n"
+    );
+}
+
+#[test]
+fn sanitize_output_escapes_control_characters() {
+    let program = "27 chr \"[31mred\" concat print \"tab\\tok\\n\" print 7 chr print 133 chr print";
+
+    let output = kcats(&["--sanitize-output", "-"], program);
+    assert_eq!(stdout(&output), "\\x1b[31mredtab\tok\n\\x07\\u{85}");
+
+    let output = kcats(&["-"], program);
+    assert_eq!(stdout(&output), "\x1b[31mredtab\tok\n\x07\u{85}");
+}
@@ -0,0 +1,10 @@
+// the diff --self-test shows when a fixture prints something else than expected
+
+use kcats::render_diff;
+
+#[test]
+fn diff_marks_changed_lines() {
+    assert_eq!(render_diff("a\nb\nc\n", "a\nx\nc\nd\n", false), "  a\n- b\n+ x\n  c\n+ d\n");
+    assert_eq!(render_diff("a\nb\n", "a\n", false), "  a\n- b\n");
+    assert_eq!(render_diff("a\n", "b\n", true), "\x1b[31m- a\x1b[0m\n\x1b[32m+ b\x1b[0m\n");
+}
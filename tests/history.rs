@@ -0,0 +1,25 @@
+// the REPL history file, saved and loaded again
+
+use kcats::History;
+
+#[test]
+fn history_round_trip() {
+    let path = std::env::temp_dir().join(format!("kcats-history-{}", std::process::id())).join("history");
+    let _ = std::fs::remove_dir_all(path.parent().unwrap());
+
+    let mut history = History::load(path.clone()).unwrap();
+    assert!(history.lines().is_empty());
+    history.push("1 2 +");
+    history.push("1 2 +");
+    history.push("   ");
+    history.push("\"hi\" print");
+    history.save().unwrap();
+
+    let mut history = History::load(path.clone()).unwrap();
+    assert_eq!(history.lines(), ["1 2 +", "\"hi\" print"]);
+    // a repeat of the line loaded last is left out too
+    history.push("\"hi\" print");
+    assert_eq!(history.lines().len(), 2);
+
+    std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+}
@@ -0,0 +1,117 @@
+// runs each program in tests/programs/ and compares what it printed with the `.out` file
+// next to it, or its error with the `.err` file for programs that are expected to fail
+
+use std::{fs, path::Path};
+
+fn check_program(path: &Path) -> Result<(), String> {
+    let source = fs::read_to_string(path).map_err(|e| format!("failed to read: {e}"))?;
+    let expected_out = fs::read_to_string(path.with_extension("out")).ok();
+    let expected_err = fs::read_to_string(path.with_extension("err")).ok();
+
+    match (kcats::run(&source), expected_out, expected_err) {
+        (Ok(output), Some(expected), _) if output == expected => Ok(()),
+        (Err(e), _, Some(expected)) if e.to_string() == expected.trim_end() => Ok(()),
+        (_, None, None) => Err("has neither a .out nor a .err file".to_string()),
+        (Ok(output), Some(expected), _) => Err(format!("printed\n{output}\nbut expected\n{expected}")),
+        (Ok(output), None, Some(expected)) => Err(format!("succeeded, printing\n{output}\nbut expected the error\n{expected}")),
+        (Err(e), _, Some(expected)) => Err(format!("failed with\n{e}\nbut expected the error\n{expected}")),
+        (Err(e), Some(_), None) => Err(format!("failed with\n{e}")),
+    }
+}
+
+#[test]
+fn programs() {
+    let mut paths: Vec<_> = fs::read_dir(Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/programs"))
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|extension| extension == "kc"))
+        .collect();
+    paths.sort();
+    assert!(!paths.is_empty(), "no programs in tests/programs");
+
+    let failures: Vec<_> = paths.iter()
+        .filter_map(|path| check_program(path).err().map(|e| format!("{}: {e}", path.display())))
+        .collect();
+    assert!(failures.is_empty(), "{} of {} programs diverged:\n\n{}", failures.len(), paths.len(), failures.join("\n\n"));
+}
+
+#[test]
+fn instruction_limit_stops_an_infinite_loop() {
+    let options = kcats::Options { max_instructions: Some(10_000), ..kcats::Options::default() };
+
+    let error = kcats::run_source("<source>", ":loop loop", options).err().expect("the loop should be stopped");
+    assert_eq!(error.to_string(), "<source>:1:7: instruction limit of 10000 exceeded");
+}
+
+#[test]
+fn stack_and_value_limits_stop_runaway_growth() {
+    let options = kcats::Options { max_stacks: Some(50), ..kcats::Options::default() };
+    let error = kcats::run_source("<source>", ":loop -> loop", options).err().expect("the loop should be stopped");
+    assert_eq!(error.to_string(), "<source>:1:7: attempted to create more than the maximum of 50 stacks");
+
+    let options = kcats::Options { max_values: Some(1000), ..kcats::Options::default() };
+    let error = kcats::run_source("<source>", ":loop 1 loop", options).err().expect("the loop should be stopped");
+    assert_eq!(error.to_string(), "<source>:1:9: the stacks hold 1024 values, more than the maximum of 1000");
+}
+
+#[test]
+fn assert_empty_at_exit() {
+    let options = || kcats::Options { assert_empty_at_exit: true, ..kcats::Options::default() };
+
+    let error = kcats::run_source("<source>", "-> 1 2 <-", options()).err().expect("stack 1 is not empty");
+    assert_eq!(error.to_string(), "stack 1 is not empty at exit: [\"1\", \"2\"]");
+
+    let error = kcats::run_source("<source>", "1 \"b\" spill 2 3 \"a\" spill", options()).err().expect("the stacks are not empty");
+    assert_eq!(error.to_string(), "named stacks are not empty at exit: `a` [\"2\", \"3\"], `b` [\"1\"]");
+
+    assert!(kcats::run_source("<source>", "1 \"a\" spill \"a\" fill pop -> 2 pop <-", options()).is_ok());
+}
+
+#[test]
+fn value_size_limit() {
+    let options = || kcats::Options { max_value_size: Some(10), capture_output: true, ..kcats::Options::default() };
+
+    let error = kcats::run_source("<source>", "97 100 repeat_char", options()).err().expect("the value is too large");
+    assert_eq!(error.to_string(), "<source>:1:8: value of 100 bytes exceeds the maximum value size of 10 bytes");
+
+    let finished = kcats::run_source("<source>", "97 10 repeat_char \"t\" tag print", options()).unwrap();
+    assert_eq!(finished.output, "aaaaaaaaaa");
+}
+
+#[test]
+fn parse_num_rejects_malformed_numbers() {
+    for string in ["0x", "0b2", "-"] {
+        let error = kcats::run(&format!("{string:?} parse_num")).expect_err(string);
+        assert_eq!(error.to_string(), format!("<source>:1:{}: failed to parse {string:?} as a number", string.len() + 4));
+    }
+}
+
+#[test]
+fn depth_per_stack_limit() {
+    let options = |max| kcats::Options { max_depth_per_stack: Some(max), ..kcats::Options::default() };
+
+    let error = kcats::run_source("<source>", "1 2 3 4 5", options(4)).err().expect("the stack is too deep");
+    assert_eq!(error.to_string(), "<source>:1:9: stack 0 would exceed the maximum depth of 4 values");
+
+    // insert takes two values and gives back one, so the stack is one short of the limit after it
+    assert!(kcats::run_source("<source>", "1 2 3 \"x\" 1 insert 9", options(5)).is_ok());
+    let error = kcats::run_source("<source>", "1 2 3 \"x\" 1 insert 9 9", options(5)).err().expect("the stack is too deep");
+    assert_eq!(error.to_string(), "<source>:1:22: stack 0 would exceed the maximum depth of 5 values");
+}
+
+#[test]
+fn library_runs_print_no_warnings() {
+    let options = kcats::Options { capture_output: true, ..kcats::Options::default() };
+    assert!(!options.warnings);
+    let finished = kcats::run_source("<source>", "\"a\" print 5 jump \"b\" print \"c\" print", options).unwrap();
+    assert_eq!(finished.output, "ac");
+}
+
+#[test]
+fn pop_on_an_empty_stack_is_a_stack_underflow() {
+    let error = kcats::run("1 pop pop").expect_err("the second pop has nothing to pop");
+    let kcats::RuntimeError::At { line: 1, column: 7, error, .. } = error else {
+        panic!("expected the error to be located, got {error:?}");
+    };
+    assert!(matches!(*error, kcats::RuntimeError::StackUnderflow { stack: 0, needed: 1, available: 0 }), "{error:?}");
+}
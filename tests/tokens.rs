@@ -0,0 +1,21 @@
+// programs built as tokens, without going through the lexer
+
+use kcats::{run_tokens, Token};
+
+#[test]
+fn run_tokens_directly() {
+    let tokens = vec![
+        Token::Int(1),
+        Token::Int(2),
+        Token::Ident("+".to_string()),
+        Token::Int(3),
+        Token::Ident("==".to_string()),
+        Token::Str("1 2 + is 3".to_string()),
+        Token::Ident("assert".to_string()),
+    ];
+    assert!(run_tokens(tokens).is_ok());
+
+    let tokens = vec![Token::Int(1), Token::Int(0), Token::Ident("/".to_string())];
+    let error = run_tokens(tokens).expect_err("dividing by zero fails");
+    assert_eq!(error.to_string(), "division by zero in `/`: 1 / 0");
+}